@@ -0,0 +1,6779 @@
+//! Core of arch-assist: intent translation, safety validation, package resolution and
+//! command execution. The `arch-assist` binary is a thin CLI over this crate.
+
+use std::io::{self, IsTerminal, Write};
+use std::os::fd::AsFd;
+use std::process::{Command, ExitStatus, Stdio};
+
+use reqwest::blocking::Client as HttpClient;
+use serde::{Deserialize, Serialize};
+use shell_words::split as shell_split;
+use thiserror::Error;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Error)]
+pub enum AssistError {
+    #[error("unsafe command blocked: {0}")]
+    Unsafe(String),
+    #[error("command failed: {0}")]
+    CommandFailed(String),
+    /// HTTP failures and unusable LLM replies, so callers can retry later
+    #[error("network error: {0}")]
+    Network(String),
+    /// A command ran and exited nonzero; its code is passed on as arch-assist's own
+    #[error("command failed: {message}")]
+    Exited { message: String, code: i32 },
+}
+
+impl AssistError {
+    /// Process exit status for this error: 1 command failed, 2 unsafe, 3 network/LLM, or the
+    /// failing command's own code.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AssistError::CommandFailed(_) => 1,
+            AssistError::Unsafe(_) => 2,
+            AssistError::Network(_) => 3,
+            AssistError::Exited { code, .. } => *code,
+        }
+    }
+
+    /// `Exited` when the process returned a code, `CommandFailed` when a signal killed it.
+    fn from_status(message: String, status: ExitStatus) -> Self {
+        match status.code() {
+            Some(code) => AssistError::Exited { message, code },
+            None => AssistError::CommandFailed(message),
+        }
+    }
+}
+
+/// Whether ANSI color may be used at all. `--json` consumers never get escapes.
+pub fn color_allowed(no_color: bool, json: bool) -> bool {
+    !no_color && !json && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+#[derive(Clone, Copy)]
+pub enum Paint {
+    Bold,
+    Dim,
+    Red,
+    Green,
+    Yellow,
+}
+
+impl Paint {
+    fn code(self) -> &'static str {
+        match self {
+            Paint::Bold => "1",
+            Paint::Dim => "2",
+            Paint::Red => "31",
+            Paint::Green => "32",
+            Paint::Yellow => "33",
+        }
+    }
+
+    /// Wraps `text` in escapes only when color is allowed and the target stream is a terminal.
+    pub fn apply(self, text: &str, color: bool, stderr: bool) -> String {
+        let tty = if stderr {
+            io::stderr().is_terminal()
+        } else {
+            io::stdout().is_terminal()
+        };
+        if color && tty {
+            format!("\x1b[{}m{text}\x1b[0m", self.code())
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Effective settings for one invocation: CLI flags merged over `config.toml`.
+pub struct ExecConfig {
+    pub dry_run: bool,
+    pub auto: bool,
+    pub offline: bool,
+    pub yes: bool,
+    pub prefer_paru: bool,
+    pub no_sudo: bool,
+    pub use_doas: bool,
+    pub capture: bool,
+    pub quiet: bool,
+    pub verbose: bool,
+    pub json: bool,
+    pub color: bool,
+    pub interactive: bool,
+    pub history: bool,
+    pub suggestions: bool,
+    pub reinstall: bool,
+    pub strict_distro: bool,
+    pub explain_before_run: bool,
+    pub allow_pipes: bool,
+    pub prefer_flatpak: bool,
+    pub notify: bool,
+    pub snapshot: bool,
+    pub pager: bool,
+    /// `--non-interactive`: `--noconfirm` on every package operation, without skipping our own confirmation
+    pub non_interactive: bool,
+    /// `--assume-aur` / `--assume-repo`: every package gets this origin without any lookup
+    pub assume_origin: Option<PackageOrigin>,
+    /// `--max-commands`: suggestions past this many are dropped before printing or running
+    pub max_commands: Option<usize>,
+    /// `--model`; beats `OPENAI_MODEL`/`OLLAMA_MODEL` and the config file
+    pub model_override: Option<String>,
+    pub model: Option<String>,
+    pub backend: Backend,
+    pub timeout: u64,
+    pub retries: u32,
+    pub package_cache_ttl: Duration,
+    pub llm_cache_ttl: Duration,
+    pub system_prompt: Option<String>,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    pub aur_helper: Option<String>,
+    pub extra_allowed: Vec<String>,
+    pub extra_forbidden: Vec<String>,
+    /// Names added to the built-in AUR guess list (`common_aur.txt`)
+    pub extra_aur_packages: Vec<String>,
+    /// `extra_pacman_args` plus `--pacman-arg`, inserted before the targets of install, removal and
+    /// upgrade operations
+    pub extra_pacman_args: Vec<String>,
+    /// `--sudo-askpass`: program sudo runs (`sudo -A`) to get the password without a terminal
+    pub sudo_askpass: Option<String>,
+}
+
+impl ExecConfig {
+    pub const DEFAULT_TEMPERATURE: f32 = 1.0;
+    pub const DEFAULT_MAX_TOKENS: u32 = 150;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    OpenAi,
+    Ollama,
+}
+
+impl Backend {
+    pub fn parse(value: &str) -> Result<Self, AssistError> {
+        match value.trim().to_lowercase().as_str() {
+            "openai" => Ok(Backend::OpenAi),
+            "ollama" => Ok(Backend::Ollama),
+            other => Err(AssistError::CommandFailed(format!(
+                "unknown backend '{other}' (expected openai or ollama)"
+            ))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::OpenAi => "openai",
+            Backend::Ollama => "ollama",
+        }
+    }
+}
+
+/// Defaults read from `config.toml`; CLI flags are OR-ed on top.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct FileConfig {
+    pub dry_run: bool,
+    pub auto: bool,
+    pub offline: bool,
+    pub yes: bool,
+    pub prefer_paru: bool,
+    pub no_sudo: bool,
+    pub use_doas: bool,
+    pub capture: bool,
+    pub interactive: bool,
+    pub no_history: bool,
+    pub no_suggestions: bool,
+    pub quiet: bool,
+    pub verbose: bool,
+    pub strict_distro: bool,
+    pub explain_before_run: bool,
+    pub allow_pipes: bool,
+    pub prefer_flatpak: bool,
+    pub notify: bool,
+    pub snapshot: bool,
+    pub pager: bool,
+    pub non_interactive: bool,
+    pub model: Option<String>,
+    pub backend: Option<String>,
+    pub package_cache_ttl_hours: Option<u64>,
+    pub llm_cache_ttl_hours: Option<u64>,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub aur_helper: Option<String>,
+    pub extra_allowed: Vec<String>,
+    pub extra_forbidden: Vec<String>,
+    pub extra_aur_packages: Vec<String>,
+    pub extra_pacman_args: Vec<String>,
+    pub sudo_askpass: Option<String>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("arch-assist").join("config.toml"))
+}
+
+/// Reads `explicit` (`--config`) or the XDG default, with `[profile.<name>]` merged over the
+/// base keys when `--profile` names one. Only the default may be missing, and only when no
+/// profile is asked for; a path given on the command line has to exist and parse.
+pub fn load_config(explicit: Option<&Path>, profile: Option<&str>) -> Result<FileConfig, AssistError> {
+    let Some(path) = explicit.map(Path::to_path_buf).or_else(config_path) else {
+        return match profile {
+            Some(name) => Err(AssistError::CommandFailed(format!("profile '{name}': no config file location"))),
+            None => Ok(FileConfig::default()),
+        };
+    };
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && explicit.is_none() && profile.is_none() => {
+            return Ok(FileConfig::default())
+        }
+        Err(e) => {
+            return Err(AssistError::CommandFailed(format!(
+                "read config {} ({e})",
+                path.display()
+            )))
+        }
+    };
+    parse_config(&data, profile, &path)
+}
+
+/// Parses config text, replacing base keys with those of `[profile.<name>]` when a profile is
+/// selected. The `profile` tables themselves never reach `FileConfig`.
+fn parse_config(data: &str, profile: Option<&str>, path: &Path) -> Result<FileConfig, AssistError> {
+    let parse_error = |e: toml::de::Error| AssistError::CommandFailed(format!("parse config {} ({e})", path.display()));
+    let mut table: toml::Table = toml::from_str(data).map_err(parse_error)?;
+    let profiles = table.remove("profile");
+    if let Some(name) = profile {
+        let overrides = profiles
+            .as_ref()
+            .and_then(|p| p.get(name))
+            .and_then(toml::Value::as_table)
+            .ok_or_else(|| {
+                let known: Vec<&str> = profiles
+                    .as_ref()
+                    .and_then(toml::Value::as_table)
+                    .map(|t| t.keys().map(String::as_str).collect())
+                    .unwrap_or_default();
+                let known = if known.is_empty() { "none".to_string() } else { known.join(", ") };
+                AssistError::CommandFailed(format!(
+                    "profile '{name}' not found in {} (defined: {known})",
+                    path.display()
+                ))
+            })?;
+        table.extend(overrides.clone());
+    }
+    toml::Value::Table(table).try_into().map_err(parse_error)
+}
+
+pub struct AppState {
+    pub config: ExecConfig,
+    pub installed_file: PathBuf,
+    pub installed: HashSet<String>,
+    /// Prompt that produced the commands being run, for the history log
+    pub prompt: Option<String>,
+}
+
+/// Turns a prompt into commands: builtin intents first, then the configured LLM backend.
+/// Returns the suggestions along with their source (`builtin`, `openai`, `ollama`).
+fn suggest(
+    prompt: &str,
+    config: &ExecConfig,
+    installed: &HashSet<String>,
+    resolver: &dyn PackageResolver,
+) -> Result<(Vec<Suggestion>, &'static str), AssistError> {
+    let (mut commands, source) = raw_suggestions(prompt, config, installed, resolver)?;
+    if config.snapshot {
+        commands = with_snapshot(commands, config, find_in_path("snapper").is_some());
+    }
+    Ok((cap_commands(commands, config), source))
+}
+
+fn raw_suggestions(
+    prompt: &str,
+    config: &ExecConfig,
+    installed: &HashSet<String>,
+    resolver: &dyn PackageResolver,
+) -> Result<(Vec<Suggestion>, &'static str), AssistError> {
+    if let Some(commands) = builtin_translate(prompt, config, installed, resolver)? {
+        return Ok((commands, "builtin"));
+    }
+    if let Some(problem) = llm_unavailable(config) {
+        // A first run without a key is common; give builtins a second chance before failing
+        let relaxed = relax_prompt(prompt);
+        if relaxed != prompt.trim().to_lowercase() {
+            if let Some(commands) = builtin_translate(&relaxed, config, installed, resolver)? {
+                return Ok((commands, "builtin"));
+            }
+        }
+        return Err(AssistError::CommandFailed(no_llm_message(&problem)));
+    }
+    let commands = llm_translate(prompt, config, installed, resolver)?
+        .into_iter()
+        .map(|(cmd, reason)| Suggestion {
+            cmd,
+            reason: reason.map_or(Cow::Borrowed(LLM_REASON), Cow::Owned),
+        })
+        .collect();
+    Ok((commands, config.backend.name()))
+}
+
+/// `--snapshot`: a snapper snapshot right before the first install or upgrade in the batch.
+/// Without snapper on PATH the batch is left alone and a warning says so.
+fn with_snapshot(mut commands: Vec<Suggestion>, config: &ExecConfig, have_snapper: bool) -> Vec<Suggestion> {
+    let Some(at) = commands.iter().position(|s| changes_packages(&s.cmd)) else {
+        return commands;
+    };
+    if !have_snapper {
+        let line = "warning: --snapshot needs snapper; continuing without a snapshot";
+        eprintln!("{}", Paint::Yellow.apply(line, config.color, true));
+        return commands;
+    }
+    commands.insert(
+        at,
+        Suggestion {
+            cmd: format!("{} snapper create --description 'arch-assist pre-op'", privilege(config)),
+            reason: "snapshot before packages change".into(),
+        },
+    );
+    commands
+}
+
+/// Installs and upgrades from a repo or the AUR, plus `pacman -U` of a local/cached file.
+fn changes_packages(cmd: &str) -> bool {
+    let (program, args) = split_program(cmd);
+    is_network_pkg_op(cmd)
+        || (program == "pacman"
+            && args.iter().any(|a| a.starts_with('-') && !a.starts_with("--") && a.contains('U')))
+}
+
+/// `--max-commands`: keeps the first N suggestions and says on stderr how many were cut.
+fn cap_commands(mut commands: Vec<Suggestion>, config: &ExecConfig) -> Vec<Suggestion> {
+    let Some(max) = config.max_commands.filter(|max| commands.len() > *max) else {
+        return commands;
+    };
+    let omitted = commands.split_off(max);
+    let line = format!(
+        "note: {} command(s) omitted by --max-commands {max}: {}",
+        omitted.len(),
+        omitted.iter().map(|s| s.cmd.as_str()).collect::<Vec<_>>().join("; ")
+    );
+    eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+    commands
+}
+
+const PROMPT_FILLER: [&str; 9] = [
+    "please ", "can you ", "could you ", "would you ", "i want to ", "i need to ", "help me ", "how do i ", "how to ",
+];
+
+/// Why the LLM cannot be asked right now, if it cannot: offline with a remote API, or no API key.
+fn llm_unavailable(config: &ExecConfig) -> Option<String> {
+    if !llm_reachable(config) {
+        return Some("offline mode: LLM suggestions disabled".to_string());
+    }
+    let base = openai_base_url();
+    if config.backend == Backend::OpenAi && std::env::var_os("OPENAI_API_KEY").is_none() && !is_local_url(&base) {
+        return Some(
+            "OPENAI_API_KEY not set (export it, use ARCH_ASSIST_BACKEND=ollama for a local model, \
+             or pass --offline to resolve packages without the LLM)"
+                .to_string(),
+        );
+    }
+    None
+}
+
+fn no_llm_message(problem: &str) -> String {
+    let usages: Vec<&str> = BUILTIN_RULES.iter().map(|rule| rule.usage).collect();
+    format!("{problem}\nBuilt-in prompts that work without it:\n  {}", usages.join("\n  "))
+}
+
+/// Lowercases and strips conversational filler ("please", "can you", a trailing "?") so a
+/// builtin intent phrased politely still matches.
+fn relax_prompt(prompt: &str) -> String {
+    let mut text = prompt.trim().to_lowercase();
+    loop {
+        let trimmed = text.trim_end_matches(['?', '.', '!']).trim_end();
+        let trimmed = trimmed.strip_suffix(" please").unwrap_or(trimmed);
+        let trimmed = PROMPT_FILLER
+            .iter()
+            .find_map(|filler| trimmed.strip_prefix(filler))
+            .unwrap_or(trimmed)
+            .trim()
+            .to_string();
+        if trimmed == text {
+            return text;
+        }
+        text = trimmed;
+    }
+}
+
+/// Library entry point: suggestions for `prompt` without printing or running anything.
+/// Only `pacman -Q` is consulted for installed packages.
+pub fn translate(prompt: &str, config: &ExecConfig) -> Result<Vec<Suggestion>, AssistError> {
+    suggest(prompt, config, &HashSet::new(), &HttpResolver { config }).map(|(commands, _)| commands)
+}
+
+pub fn handle_prompt(prompt: &str, state: &mut AppState, runner: &dyn CommandRunner) -> Result<(), AssistError> {
+    state.prompt = Some(prompt.to_string());
+    let resolver = HttpResolver { config: &state.config };
+    let (commands, source) = suggest(prompt, &state.config, &state.installed, &resolver)?;
+    print_suggestions(&commands, source, &state.config)?;
+
+    // Suggest but do not run unless explicitly requested
+    if state.config.auto {
+        let outcome = execute_suggestions(commands.clone(), state, runner);
+        if state.config.notify && !state.config.dry_run {
+            notify_outcome(&outcome, commands.len(), &state.config);
+        }
+        outcome?;
+    }
+    if state.config.dry_run && commands.len() > 1 && !state.config.quiet && !state.config.json {
+        print!("{}", plan_summary(&commands));
+    }
+    Ok(())
+}
+
+/// `translate`: the builtin rules alone. Never asks an LLM, and prints "no builtin match"
+/// (or `[]` under `--json`) when no rule fires.
+pub fn handle_translate(prompt: &str, state: &AppState) -> Result<Option<Vec<Suggestion>>, AssistError> {
+    let resolver = HttpResolver { config: &state.config };
+    let commands = builtin_translate(prompt, &state.config, &state.installed, &resolver)?;
+    match &commands {
+        Some(commands) => print_suggestions(commands, "builtin", &state.config)?,
+        None if state.config.json => println!("[]"),
+        None => println!("no builtin match"),
+    }
+    Ok(commands)
+}
+
+/// The closing `--dry-run` block: every command in order, split into network and local.
+fn plan_summary(commands: &[Suggestion]) -> String {
+    let (network, local): (Vec<&Suggestion>, Vec<&Suggestion>) =
+        commands.iter().partition(|s| needs_network(&s.cmd));
+    let mut out = format!("\nPlan: {} commands would run\n", commands.len());
+    for (label, group) in [("network", &network), ("local", &local)] {
+        if group.is_empty() {
+            continue;
+        }
+        out.push_str(&format!("  {label} ({}):\n", group.len()));
+        for sugg in group {
+            out.push_str(&format!("    {}\n", sugg.cmd));
+        }
+    }
+    if network.is_empty() {
+        out.push_str("  --offline would block none of them\n");
+    } else {
+        out.push_str(&format!("  --offline would block the {} network command(s)\n", network.len()));
+    }
+    out
+}
+
+/// Feeds each line of `script` to `handle_prompt`, skipping blank lines and `#` comments.
+/// Stops at the first failing prompt so a setup list never runs past a broken step.
+pub fn handle_prompt_script(script: &str, state: &mut AppState, runner: &dyn CommandRunner) -> Result<(), AssistError> {
+    let prompts = script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for prompt in prompts {
+        if !state.config.quiet && !state.config.json {
+            println!("{}", Paint::Bold.apply(&format!("==> {prompt}"), state.config.color, false));
+        }
+        handle_prompt(prompt, state, runner)?;
+    }
+    Ok(())
+}
+
+fn print_suggestions(commands: &[Suggestion], source: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    if config.json {
+        return print_json(commands, source);
+    }
+    if config.quiet {
+        // `run` echoes each command as it executes; without --auto the bare list is the plan
+        if !config.auto {
+            for sugg in commands {
+                println!("{}", sugg.cmd);
+            }
+        }
+        return Ok(());
+    }
+    for sugg in commands {
+        let note = if source == "builtin" {
+            format!("# {}", sugg.reason)
+        } else if sugg.reason == LLM_REASON {
+            format!("# from {source}")
+        } else {
+            format!("# {} (from {source})", sugg.reason)
+        };
+        println!(
+            "{}    {}",
+            Paint::Bold.apply(&sugg.cmd, config.color, false),
+            Paint::Dim.apply(&note, config.color, false)
+        );
+    }
+    Ok(())
+}
+
+/// Confirms and runs the batch; returns how many commands actually ran.
+fn execute_suggestions(
+    commands: Vec<Suggestion>,
+    state: &mut AppState,
+    runner: &dyn CommandRunner,
+) -> Result<usize, AssistError> {
+    // Several downgrade candidates are a menu: always ask, and stop after the first one picked
+    let pick_one = commands.len() > 1 && commands.iter().all(|s| s.reason == DOWNGRADE_REASON);
+    // ssh-keygen writes key files and a raw `kill` (extra_allowed) skips kill-process's checks,
+    // so both are confirmed step by step even with --yes
+    let always_ask = commands.iter().any(|s| ["ssh-keygen", "kill"].contains(&program_token(&s.cmd)));
+    let mut ask = pick_one || always_ask || (state.config.interactive && !state.config.yes);
+    if !ask {
+        match confirm(&commands, &state.config)? {
+            BatchChoice::Run => {}
+            BatchChoice::Abort => return Ok(0),
+            // Editing happens per command, so drop into the step-by-step flow
+            BatchChoice::Review => ask = true,
+        }
+    }
+
+    let mut ran = 0;
+    for mut sugg in commands {
+        if ask {
+            match confirm_step(&mut sugg, &state.config)? {
+                // confirm_step resolves edits itself and returns Yes with the new command
+                StepChoice::Yes | StepChoice::Edit => {}
+                StepChoice::No => continue,
+                StepChoice::All => ask = false,
+                StepChoice::Quit => break,
+            }
+        }
+        ensure_offline_ok(&sugg, &state.config)?;
+        validate(&sugg.cmd, &state.config)?;
+        if state.config.explain_before_run {
+            annotate(&sugg, &state.config);
+        }
+        run(&sugg.cmd, state, runner)?;
+        ran += 1;
+        if pick_one {
+            break;
+        }
+    }
+    Ok(ran)
+}
+
+/// `--notify`: a desktop notification once an `--auto` batch is done. Under `--quiet` only
+/// failures notify; a declined batch (nothing ran) never does.
+fn notify_outcome(outcome: &Result<usize, AssistError>, total: usize, config: &ExecConfig) {
+    let (urgency, body) = match outcome {
+        Ok(0) => return,
+        Ok(_) if config.quiet => return,
+        Ok(ran) => ("normal", format!("Finished: {ran} of {total} commands ran")),
+        Err(e) => ("critical", format!("Failed: {e}")),
+    };
+    if find_in_path("notify-send").is_none() {
+        eprintln!("warning: --notify needs notify-send (libnotify); skipping the notification");
+        return;
+    }
+    // notify-send exits nonzero when no notification daemon is running
+    let sent = Command::new("notify-send")
+        .args(["--app-name=arch-assist", "--urgency", urgency, "arch-assist", &body])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if !sent.is_ok_and(|s| s.success()) {
+        eprintln!("warning: --notify: no notification daemon answered; skipping the notification");
+    }
+}
+
+fn installer_for(pkg: &str, config: &ExecConfig) -> Result<String, AssistError> {
+    Ok(if config.prefer_paru || pkg.ends_with("-bin") {
+        aur_helper(config)?.to_string()
+    } else {
+        pacman_cmd(config)
+    })
+}
+
+/// `sudo` or `doas`, depending on `--use-doas`.
+fn privilege(config: &ExecConfig) -> &'static str {
+    if config.use_doas {
+        "doas"
+    } else {
+        "sudo"
+    }
+}
+
+fn pacman_cmd(config: &ExecConfig) -> String {
+    if config.no_sudo {
+        "pacman".to_string()
+    } else {
+        format!("{} pacman", privilege(config))
+    }
+}
+
+fn is_privilege_wrapper(token: &str) -> bool {
+    token == "sudo" || token == "doas"
+}
+
+const AUR_HELPERS: [&str; 4] = ["paru", "yay", "pikaur", "trizen"];
+
+fn is_aur_helper(program: &str) -> bool {
+    AUR_HELPERS.contains(&program)
+}
+
+/// The helper used for AUR installs, detected once per process. An explicitly configured
+/// helper wins when it is on PATH; otherwise the first installed one of `AUR_HELPERS`.
+/// A configured name outside `AUR_HELPERS` counts only when `extra_allowed` lists it too.
+fn aur_helper(config: &ExecConfig) -> Result<&'static str, AssistError> {
+    static HELPER: OnceLock<Option<String>> = OnceLock::new();
+    HELPER
+        .get_or_init(|| detect_aur_helper(config.aur_helper.as_deref(), &config.extra_allowed))
+        .as_deref()
+        .ok_or_else(|| {
+            AssistError::CommandFailed(
+                "no AUR helper found (looked for paru, yay, pikaur, trizen); bootstrap one with: \
+sudo pacman -S --needed base-devel git, then git clone https://aur.archlinux.org/paru-bin.git \
+and run makepkg -si inside it"
+                    .into(),
+            )
+        })
+}
+
+fn detect_aur_helper(configured: Option<&str>, extra_allowed: &[String]) -> Option<String> {
+    // `AUR_HELPER=bash` must not turn bash into an allowlisted program
+    let configured = configured.filter(|name| {
+        let known = is_aur_helper(name) || extra_allowed.iter().any(|p| p == name);
+        if !known {
+            let helpers = AUR_HELPERS.join(", ");
+            eprintln!("warning: AUR helper '{name}' is not one of {helpers} and not in extra_allowed; ignoring it");
+        }
+        known
+    });
+    if let Some(name) = configured {
+        if find_in_path(name).is_some() {
+            return Some(name.to_string());
+        }
+    }
+    let found = AUR_HELPERS.iter().find(|h| find_in_path(h).is_some());
+    match (configured, found) {
+        (Some(name), Some(found)) => {
+            eprintln!("warning: AUR helper '{name}' not found on PATH; using {found}");
+            Some(found.to_string())
+        }
+        // Respect an explicit choice that simply isn't installed yet
+        (Some(name), None) => Some(name.to_string()),
+        (None, found) => found.map(|h| h.to_string()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub cmd: String,
+    pub reason: Cow<'static, str>,
+}
+
+#[derive(Serialize)]
+struct JsonSuggestion<'a> {
+    cmd: &'a str,
+    reason: &'a str,
+    source: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonIntent<'a> {
+    usage: &'a str,
+    examples: &'a [&'a str],
+    commands: Vec<String>,
+    note: Option<String>,
+}
+
+/// Package origins for `intents` examples: every name is taken as a repo package, so the
+/// listing never queries archlinux.org or the AUR.
+struct ExampleResolver;
+
+impl PackageResolver for ExampleResolver {
+    fn resolve(&self, _pkg: &str) -> Result<PackageOrigin, AssistError> {
+        Ok(PackageOrigin::Repo)
+    }
+}
+
+/// `intents`: every builtin intent with its trigger phrases and what the first one expands
+/// to under the current flags. Examples the builtins leave to the LLM say so instead.
+pub fn handle_intents(state: &AppState) -> Result<(), AssistError> {
+    let listing: Vec<JsonIntent> = BUILTIN_RULES
+        .iter()
+        .map(|rule| {
+            let expanded = builtin_translate(rule.examples[0], &state.config, &state.installed, &ExampleResolver);
+            let (commands, note) = match expanded {
+                Ok(Some(cmds)) => (cmds.into_iter().map(|s| s.cmd).collect(), None),
+                Ok(None) => (Vec::new(), Some("left to the LLM unless --offline".to_string())),
+                Err(e) => (Vec::new(), Some(e.to_string())),
+            };
+            JsonIntent {
+                usage: rule.usage,
+                examples: rule.examples,
+                commands,
+                note,
+            }
+        })
+        .collect();
+    if state.config.json {
+        let body = serde_json::to_string_pretty(&listing)
+            .map_err(|e| AssistError::CommandFailed(format!("json encode ({e})")))?;
+        println!("{body}");
+        return Ok(());
+    }
+    for intent in &listing {
+        println!("{}", Paint::Bold.apply(intent.usage, state.config.color, false));
+        let quoted: Vec<String> = intent.examples.iter().map(|e| format!("\"{e}\"")).collect();
+        println!("  {}", Paint::Dim.apply(&format!("e.g. {}", quoted.join(", ")), state.config.color, false));
+        for cmd in &intent.commands {
+            println!("  {cmd}");
+        }
+        if let Some(note) = &intent.note {
+            println!("  ({note})");
+        }
+    }
+    Ok(())
+}
+
+fn print_json(suggestions: &[Suggestion], source: &str) -> Result<(), AssistError> {
+    let out: Vec<JsonSuggestion> = suggestions
+        .iter()
+        .map(|s| JsonSuggestion {
+            cmd: &s.cmd,
+            reason: &s.reason,
+            source,
+        })
+        .collect();
+    let body = serde_json::to_string_pretty(&out)
+        .map_err(|e| AssistError::CommandFailed(format!("json encode ({e})")))?;
+    println!("{body}");
+    Ok(())
+}
+
+/// What a builtin rule sees: the prompt as typed, its lowercase form (first word and the rest),
+/// and what package resolution needs.
+struct PromptCtx<'a> {
+    prompt: &'a str,
+    lower: String,
+    rest: String,
+    config: &'a ExecConfig,
+    installed: &'a HashSet<String>,
+    resolver: &'a dyn PackageResolver,
+}
+
+impl<'a> PromptCtx<'a> {
+    fn new(
+        prompt: &'a str,
+        config: &'a ExecConfig,
+        installed: &'a HashSet<String>,
+        resolver: &'a dyn PackageResolver,
+    ) -> Self {
+        let lower = prompt.to_lowercase();
+        let rest = lower.split_whitespace().skip(1).collect::<Vec<_>>().join(" ");
+        PromptCtx {
+            prompt,
+            lower,
+            rest,
+            config,
+            installed,
+            resolver,
+        }
+    }
+
+    fn first(&self) -> &str {
+        self.lower.split_whitespace().next().unwrap_or("")
+    }
+
+    fn mentions(&self, keys: &[&str]) -> bool {
+        keys.iter().any(|k| self.lower.contains(k))
+    }
+
+    /// The first rule in `BUILTIN_RULES` that claims this prompt.
+    fn rule(&self) -> Option<&'static Rule> {
+        BUILTIN_RULES.iter().find(|rule| (rule.matches)(self))
+    }
+}
+
+type RuleOutput = Result<Option<Vec<Suggestion>>, AssistError>;
+
+/// A builtin intent: when it applies, what it expands to, and how `intents` and the no-LLM hint
+/// present it (a usage label plus prompts that trigger it, the first shown expanded). `build`
+/// returning `None` hands the prompt to the LLM without trying later rules.
+struct Rule {
+    usage: &'static str,
+    examples: &'static [&'static str],
+    matches: fn(&PromptCtx) -> bool,
+    build: fn(&PromptCtx) -> RuleOutput,
+}
+
+/// "repair <topic>" words that belong to a fix rule rather than naming a package.
+const REPAIR_TOPICS: [&str; 9] = [
+    "keyring",
+    "sound",
+    "audio",
+    "network",
+    "internet",
+    "wifi",
+    "bluetooth",
+    "time",
+    "clock",
+];
+
+/// Builtin intents in priority order; the first rule that matches decides.
+const BUILTIN_RULES: &[Rule] = &[
+    // First, since the file or command asked about may contain another rule's keyword ("firewall-cmd")
+    Rule {
+        usage: "what provides <file>",
+        examples: &["what provides libGL.so.1", "which package has dig"],
+        matches: |p| PROVIDES_PHRASES.iter().any(|phrase| p.lower.starts_with(phrase)) && !p.rest.is_empty(),
+        build: provides_rule,
+    },
+    // Ahead of the open/enable verbs, which would read "open port 22" as an app and "enable firewall" as a unit
+    Rule {
+        usage: "enable firewall / open port <n>",
+        examples: &["enable firewall", "open port 22"],
+        matches: is_firewall_prompt,
+        // Turning the firewall off is left to the LLM rather than answered with `ufw enable`
+        build: |p| {
+            if ["disable", "stop", "deactivate"].contains(&p.first()) || p.mentions(&["turn off", "switch off"]) {
+                return Ok(None);
+            }
+            firewall_suggestions(&p.lower, p.config, p.installed, p.resolver).map(Some)
+        },
+    },
+    Rule {
+        usage: "install <pkg>...",
+        examples: &["install vlc gimp"],
+        matches: |p| p.first() == "install" && !p.rest.is_empty(),
+        build: install_rule,
+    },
+    Rule {
+        usage: "orphaned packages",
+        examples: &["orphans", "orphaned packages"],
+        matches: |p| p.mentions(&["orphan"]),
+        // `pacman -Qtdq` exits 1 when nothing is orphaned, so listing happens inside the verb
+        build: |_| {
+            one_command(
+                "remove-orphans".to_string(),
+                "capture pacman -Qtdq internally, then pacman -Rns the list",
+            )
+        },
+    },
+    Rule {
+        usage: "remove <pkg>",
+        examples: &["remove vlc", "uninstall vlc"],
+        matches: |p| ["remove", "uninstall", "delete"].contains(&p.first()) && !p.rest.is_empty(),
+        build: |p| {
+            let installer = installer_for(&p.rest, p.config)?;
+            let base = if installer.contains("pacman") {
+                format!("{installer} -Rsn {}", p.rest)
+            } else {
+                format!("{installer} -R {}", p.rest)
+            };
+            one_command(apply_pkg_flags(base, p.config), "remove package")
+        },
+    },
+    Rule {
+        usage: "open <app>",
+        examples: &["open firefox", "launch firefox"],
+        matches: |p| ["open", "launch", "start"].contains(&p.first()) && !p.rest.is_empty(),
+        build: launch_rule,
+    },
+    Rule {
+        usage: "enable/disable <service>",
+        examples: &["enable sshd", "disable cups now"],
+        matches: |p| ["enable", "disable"].contains(&p.first()) && service_target(&p.rest).is_some(),
+        build: service_rule,
+    },
+    Rule {
+        usage: "kill <app>",
+        examples: &["kill firefox"],
+        matches: |p| p.first() == "kill" && !p.rest.is_empty() && !p.rest.contains(' '),
+        // The PIDs are only known at run time, so the verb looks them up and asks first
+        build: |p| {
+            one_command(
+                format!("kill-process {}", p.rest),
+                "find PIDs with pgrep -x, then kill them after confirmation",
+            )
+        },
+    },
+    Rule {
+        usage: "downgrade <pkg>",
+        examples: &["downgrade vlc"],
+        matches: |p| p.first() == "downgrade" && !p.rest.is_empty() && !p.rest.contains(' '),
+        build: downgrade_rule,
+    },
+    // This and "hold" go ahead of the substring fix rules, which would read "reinstall networkmanager"
+    // as "network"; "repair keyring" and friends name a fix rule's topic and fall through to it
+    Rule {
+        usage: "reinstall <pkg>...",
+        examples: &["reinstall vlc", "repair gimp"],
+        matches: |p| {
+            !p.rest.is_empty()
+                && (p.first() == "reinstall"
+                    || (p.first() == "repair" && !p.rest.split_whitespace().any(|w| REPAIR_TOPICS.contains(&w))))
+        },
+        build: reinstall_rule,
+    },
+    Rule {
+        usage: "hold <pkg>",
+        examples: &["hold linux", "ignore nvidia on upgrade"],
+        matches: |p| {
+            !p.rest.is_empty()
+                && (p.first() == "hold" || (p.first() == "ignore" && p.mentions(&["upgrad", "update"])))
+        },
+        build: hold_rule,
+    },
+    Rule {
+        usage: "what's broken",
+        examples: &["what's broken", "failed services"],
+        matches: |p| p.mentions(&["what's broken", "what is broken", "failed services", "failed units"]),
+        build: failures_rule,
+    },
+    Rule {
+        usage: "rebuild initramfs",
+        examples: &["rebuild initramfs", "mkinitcpio"],
+        matches: |p| p.mentions(&["initramfs", "mkinitcpio", "rebuild initrd"]),
+        // Purely local, so it stays available under --offline
+        build: |p| {
+            one_command(
+                format!("{} mkinitcpio -P", privilege(p.config)),
+                "rebuild initramfs for all kernel presets",
+            )
+        },
+    },
+    Rule {
+        usage: "update mirrors",
+        examples: &["update mirrors", "slow downloads"],
+        matches: |p| p.mentions(&["update mirrors", "slow downloads", "refresh mirrorlist", "update mirrorlist"]),
+        build: |p| {
+            one_command(
+                format!(
+                    "{} reflector --latest 20 --sort rate --save /etc/pacman.d/mirrorlist",
+                    privilege(p.config)
+                ),
+                "rank the 20 most recently synced mirrors by speed",
+            )
+        },
+    },
+    Rule {
+        usage: "unlock pacman database",
+        examples: &["unable to lock database", "db.lck"],
+        matches: |p| p.mentions(&["database is locked", "unable to lock database", "db.lck", "locked database"]),
+        build: |_| {
+            one_command(
+                "unlock-pacman-db".to_string(),
+                "check for a running pacman, then remove /var/lib/pacman/db.lck after confirmation",
+            )
+        },
+    },
+    Rule {
+        usage: "fix keyring",
+        examples: &["fix keyring", "invalid or corrupted package"],
+        matches: |p| p.mentions(&["keyring", "signature", "invalid or corrupted"]),
+        build: keyring_rule,
+    },
+    Rule {
+        usage: "fix sound",
+        examples: &["fix sound", "fix audio"],
+        matches: |p| p.mentions(&["fix sound", "fix audio", "sound"]),
+        build: sound_rule,
+    },
+    Rule {
+        usage: "fix network",
+        examples: &["fix network", "fix internet"],
+        matches: |p| p.mentions(&["fix internet", "fix network", "network"]),
+        build: network_rule,
+    },
+    Rule {
+        usage: "set timezone <Area/City>",
+        examples: &["set timezone Europe/London"],
+        matches: |p| p.lower.starts_with("set timezone") || p.lower.starts_with("change timezone"),
+        build: timezone_rule,
+    },
+    Rule {
+        usage: "set hostname <name>",
+        examples: &["set hostname archbox"],
+        matches: |p| p.lower.starts_with("set hostname") || p.lower.starts_with("change hostname"),
+        build: hostname_rule,
+    },
+    Rule {
+        usage: "set locale <xx_XX.charset>",
+        examples: &["set locale en_US.UTF-8"],
+        matches: |p| p.lower.starts_with("set locale") || p.lower.starts_with("change locale"),
+        build: locale_rule,
+    },
+    Rule {
+        usage: "set keymap <map>",
+        examples: &["set keymap uk"],
+        matches: |p| p.lower.starts_with("set keymap") || p.lower.starts_with("change keymap"),
+        build: keymap_rule,
+    },
+    Rule {
+        usage: "fix time",
+        examples: &["fix time", "time sync"],
+        matches: |p| p.mentions(&["fix time", "time sync", "clock"]),
+        build: ntp_rule,
+    },
+    Rule {
+        usage: "check updates",
+        examples: &["check updates", "pending updates"],
+        matches: |p| p.mentions(&["check updates", "check for updates", "available updates", "pending updates"]),
+        // checkupdates works on a temporary db copy and never refreshes the real one, so offline allows it
+        build: |_| one_command("checkupdates".to_string(), "list pending updates (pacman-contrib)"),
+    },
+    Rule {
+        usage: "upgrade system",
+        examples: &["upgrade system", "update system"],
+        matches: |p| p.mentions(&["upgrade system", "update system"]) || p.first() == "upgrade",
+        build: |p| {
+            let installer = installer_for("base", p.config)?;
+            one_command(apply_pkg_flags(format!("{installer} -Syu"), p.config), "upgrade system packages")
+        },
+    },
+    Rule {
+        usage: "list installed",
+        examples: &["list installed", "list installed explicitly containing python"],
+        matches: |p| p.mentions(&["list installed", "what's installed", "what is installed"]),
+        build: list_installed_rule,
+    },
+    Rule {
+        usage: "disk space",
+        examples: &["disk space", "disk full"],
+        matches: |p| p.mentions(&["disk space", "disk full", "storage", "out of space"]),
+        build: disk_rule,
+    },
+    Rule {
+        usage: "list drives",
+        examples: &["list drives", "block devices"],
+        matches: |p| p.mentions(&["list drives", "list disks", "block devices", "list partitions"]),
+        build: |_| one_command("lsblk -f".to_string(), "list disks and partitions with filesystems and mount points"),
+    },
+    Rule {
+        usage: "mount <device>",
+        examples: &["mount sdb1", "mount /dev/nvme0n1p2"],
+        matches: |p| p.first() == "mount" && !p.rest.is_empty() && !p.rest.contains(' '),
+        build: |p| {
+            let device = if p.rest.starts_with("/dev/") { p.rest.clone() } else { format!("/dev/{}", p.rest) };
+            if !is_block_device(&device) {
+                return Err(AssistError::CommandFailed(format!(
+                    "'{}' is not a disk or partition (expected e.g. sdb1, nvme0n1p2, mmcblk0p1)",
+                    p.rest
+                )));
+            }
+            one_command(format!("udisksctl mount -b {device}"), "mount as your user through udisks (no sudo)")
+        },
+    },
+    Rule {
+        usage: "generate ssh key",
+        examples: &["generate ssh key", "create ssh key"],
+        matches: |p| p.mentions(&["ssh key", "ssh-key", "ssh keygen"]),
+        build: ssh_key_rule,
+    },
+    Rule {
+        usage: "clean cache",
+        examples: &["clean cache", "clear cache"],
+        matches: |p| p.mentions(&["clean cache", "cleanup", "clear cache"]),
+        build: |p| {
+            let installer = installer_for("base", p.config)?;
+            one_command(apply_pkg_flags(format!("{installer} -Sc"), p.config), "clean package cache")
+        },
+    },
+    Rule {
+        usage: "battery status",
+        examples: &["battery status", "power info"],
+        matches: |p| p.mentions(&["battery", "power info", "power status"]),
+        // `upower -i $(upower -e | grep BAT)` needs a shell, so the verb does both steps itself
+        build: |_| {
+            one_command(
+                "battery-status".to_string(),
+                "find the battery with upower -e, then summarize upower -i",
+            )
+        },
+    },
+    Rule {
+        usage: "wifi status",
+        examples: &["wifi status"],
+        matches: |p| p.mentions(&["wifi status", "network status"]),
+        build: wifi_status_rule,
+    },
+    Rule {
+        usage: "fix bluetooth",
+        examples: &["fix bluetooth"],
+        matches: |p| p.mentions(&["fix bluetooth", "bluetooth"]),
+        build: bluetooth_rule,
+    },
+    Rule {
+        usage: "logs <service>",
+        examples: &["logs sshd", "journal cups"],
+        matches: |p| ["logs", "journal"].contains(&p.first()) && !p.rest.is_empty(),
+        build: |p| one_command(format!("journalctl -u {} --no-pager -n 50", p.rest), "tail service logs"),
+    },
+];
+
+const PROVIDES_PHRASES: [&str; 4] = [
+    "what provides ",
+    "which package has ",
+    "which package provides ",
+    "what package provides ",
+];
+
+/// Where pacman keeps the sync databases; `<repo>.files` appear there after `pacman -Fy`.
+const PACMAN_SYNC_DIR: &str = "/var/lib/pacman/sync";
+
+fn provides_rule(p: &PromptCtx) -> RuleOutput {
+    // File names are case-sensitive (libGL.so.1), so take the last word of the original prompt
+    let query = p.prompt.split_whitespace().last().unwrap_or("");
+    if !query.chars().all(|c| c.is_ascii_alphanumeric() || "/._+-".contains(c)) {
+        return Err(AssistError::CommandFailed(format!("'{query}' is not a plain file or command name")));
+    }
+    let mut cmds = Vec::new();
+    if !files_db_synced(Path::new(PACMAN_SYNC_DIR)) {
+        cmds.push(if p.config.offline {
+            Suggestion {
+                cmd: "echo the pacman file database is not synced yet, run sudo pacman -Fy once online".to_string(),
+                reason: "pacman -F needs the file database (--offline)".into(),
+            }
+        } else {
+            Suggestion {
+                cmd: format!("{} -Fy", pacman_cmd(p.config)),
+                reason: "download the file lists pacman -F searches".into(),
+            }
+        });
+    }
+    cmds.push(Suggestion {
+        cmd: format!("pacman -F {query}"),
+        reason: "find the repo package that owns this file".into(),
+    });
+    if find_in_path("pkgfile").is_some() {
+        cmds.push(Suggestion {
+            cmd: format!("pkgfile {query}"),
+            reason: "second opinion from pkgfile's own database".into(),
+        });
+    }
+    Ok(Some(cmds))
+}
+
+/// Whether `pacman -Fy` has run: at least one `<repo>.files` database in `dir`.
+fn files_db_synced(dir: &Path) -> bool {
+    fs::read_dir(dir).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|e| e.path().extension().is_some_and(|ext| ext == "files"))
+    })
+}
+
+/// `/dev/sdb`, `/dev/vda1`, `/dev/nvme0n1p2`, `/dev/mmcblk0p1` and the like; nothing else.
+fn is_block_device(path: &str) -> bool {
+    let digits = |s: &str| -> Option<String> {
+        let tail = s.trim_start_matches(|c: char| c.is_ascii_digit());
+        (tail.len() < s.len()).then(|| tail.to_string())
+    };
+    // An optional `p<N>` partition suffix
+    let partition = |tail: String| tail.is_empty() || tail.strip_prefix('p').and_then(digits).is_some_and(|t| t.is_empty());
+    let Some(name) = path.strip_prefix("/dev/") else {
+        return false;
+    };
+    if let Some(disk) = name.strip_prefix("sd").or_else(|| name.strip_prefix("vd")) {
+        let part = disk.trim_start_matches(|c: char| c.is_ascii_lowercase());
+        return part.len() < disk.len() && part.chars().all(|c| c.is_ascii_digit());
+    }
+    if let Some(rest) = name.strip_prefix("nvme") {
+        return digits(rest)
+            .and_then(|t| t.strip_prefix('n').and_then(digits))
+            .is_some_and(partition);
+    }
+    name.strip_prefix("mmcblk").and_then(digits).is_some_and(partition)
+}
+
+fn one_command(cmd: String, reason: &'static str) -> RuleOutput {
+    Ok(Some(vec![Suggestion {
+        cmd,
+        reason: reason.into(),
+    }]))
+}
+
+fn builtin_translate(
+    prompt: &str,
+    config: &ExecConfig,
+    installed: &HashSet<String>,
+    resolver: &dyn PackageResolver,
+) -> Result<Option<Vec<Suggestion>>, AssistError> {
+    let ctx = PromptCtx::new(prompt, config, installed, resolver);
+    if ctx.lower == "test ai" {
+        return one_command("echo ai-ok".to_string(), "built-in test command");
+    }
+    match ctx.rule() {
+        Some(rule) => (rule.build)(&ctx),
+        None => Ok(None),
+    }
+}
+
+fn install_rule(p: &PromptCtx) -> RuleOutput {
+    let (config, installed) = (p.config, p.installed);
+    let pkgs = split_package_list(&p.rest);
+    let missing: Vec<&str> = pkgs
+        .iter()
+        .copied()
+        .filter(|pkg| !already_installed(pkg, config, installed))
+        .collect();
+    if missing.is_empty() {
+        return one_command("echo already installed".to_string(), "skip reinstall");
+    }
+
+    // A single name defers to the LLM for fuzzy mapping unless offline; lists are
+    // resolved here (offline falls back to literal names and the AUR heuristic).
+    if pkgs.len() == 1 && !config.offline {
+        return Ok(None);
+    }
+    let reason = if missing.len() == 1 { "install package" } else { "install packages" };
+    p.resolver.prefetch(&missing);
+    let cmds = plan_install(&missing, "-S --needed", config, |pkg| resolve_with_hints(pkg, config, p.resolver))?;
+    Ok(Some(
+        cmds.into_iter()
+            .map(|cmd| Suggestion {
+                cmd,
+                reason: reason.into(),
+            })
+            .collect(),
+    ))
+}
+
+/// "reinstall vlc": `-S` without `--needed`, so pacman rewrites the files of a package that is
+/// already installed. Origins are resolved like installs, so AUR packages go to the helper.
+fn reinstall_rule(p: &PromptCtx) -> RuleOutput {
+    let pkgs = split_package_list(&p.rest);
+    p.resolver.prefetch(&pkgs);
+    let cmds = plan_install(&pkgs, "-S", p.config, |pkg| resolve_with_hints(pkg, p.config, p.resolver))?;
+    Ok(Some(
+        cmds.into_iter()
+            // `--needed` from extra_pacman_args would turn the reinstall into a no-op
+            .map(|cmd| cmd.split_whitespace().filter(|t| *t != "--needed").collect::<Vec<_>>().join(" "))
+            .map(|cmd| Suggestion {
+                cmd,
+                reason: "reinstall, overwriting the installed files (no --needed)".into(),
+            })
+            .collect(),
+    ))
+}
+
+/// "hold linux": a one-shot `pacman -Syu --ignore`. A permanent hold is an `IgnorePkg` line in
+/// pacman.conf, which we only print; writing it would take a redirect.
+fn hold_rule(p: &PromptCtx) -> RuleOutput {
+    const STOP: [&str; 6] = ["on", "during", "when", "while", "in", "from"];
+    let pkgs: Vec<&str> = split_package_list(&p.rest)
+        .into_iter()
+        .take_while(|w| !STOP.contains(w))
+        .collect();
+    if pkgs.is_empty() {
+        return Ok(None);
+    }
+    // Appended after apply_pkg_flags so extra args cannot land between --ignore and its value
+    let upgrade = apply_pkg_flags(format!("{} -Syu", pacman_cmd(p.config)), p.config);
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{upgrade} --ignore {}", pkgs.join(",")),
+            reason: "upgrade everything else, this time only".into(),
+        },
+        Suggestion {
+            cmd: format!(
+                "echo to hold permanently, add under [options] in {PACMAN_CONF}: IgnorePkg = {}",
+                pkgs.join(" ")
+            ),
+            reason: "permanent holds are a manual pacman.conf edit".into(),
+        },
+    ]))
+}
+
+fn launch_rule(p: &PromptCtx) -> RuleOutput {
+    let (rest, config) = (&p.rest, p.config);
+    if already_installed(rest, config, p.installed) {
+        return one_command(format!("launch {rest}"), "already installed");
+    }
+
+    if config.offline {
+        if let Some(install) = build_install_command(rest, "-S --needed", config, p.resolver)? {
+            if is_installed_notice(&install) {
+                return one_command(format!("launch {rest}"), "already installed");
+            }
+            return Ok(Some(vec![
+                Suggestion {
+                    cmd: install,
+                    reason: "ensure app is installed".into(),
+                },
+                Suggestion {
+                    cmd: format!("launch {rest}"),
+                    reason: "launch app".into(),
+                },
+            ]));
+        }
+        // fallback to previous behavior if resolution failed
+        let installer = installer_for(rest, config)?;
+        return Ok(Some(vec![
+            install_cmd(&installer, rest, config, "ensure app is installed"),
+            Suggestion {
+                cmd: format!("launch {rest}"),
+                reason: "launch app".into(),
+            },
+        ]));
+    }
+
+    // Non-offline: let LLM handle fuzzy package mapping
+    Ok(None)
+}
+
+/// The service in "enable sshd and start" / "disable cups now stop": the first word that is
+/// not a connective or a start/stop hint.
+fn service_target(rest: &str) -> Option<&str> {
+    rest.split_whitespace()
+        .find(|w| !["and", "start", "stop", "now"].contains(w))
+}
+
+fn service_rule(p: &PromptCtx) -> RuleOutput {
+    let first = p.first();
+    let Some(svc) = service_target(&p.rest) else {
+        return Ok(None);
+    };
+    // Any start/stop/now word adds --now
+    let now = p.rest.split_whitespace().any(|w| ["start", "stop", "now"].contains(&w));
+    let flag = if now { " --now" } else { "" };
+    one_command(
+        format!("{} systemctl {first}{flag} {svc}", privilege(p.config)),
+        match (first, now) {
+            ("enable", true) => "enable and start service",
+            ("enable", false) => "enable service at boot",
+            (_, true) => "disable and stop service",
+            (_, false) => "disable service at boot",
+        },
+    )
+}
+
+fn downgrade_rule(p: &PromptCtx) -> RuleOutput {
+    let rest = &p.rest;
+    let installed_version = installed_version(rest);
+    let candidates: Vec<PathBuf> = cached_versions(Path::new(PACMAN_CACHE_DIR), rest)
+        .into_iter()
+        .filter(|(version, _)| Some(version) != installed_version.as_ref())
+        .map(|(_, path)| path)
+        .collect();
+    if candidates.is_empty() {
+        return one_command(
+            format!("echo no older {rest} packages in {PACMAN_CACHE_DIR}"),
+            "nothing to downgrade to",
+        );
+    }
+    Ok(Some(
+        candidates
+            .iter()
+            .map(|path| Suggestion {
+                cmd: apply_pkg_flags(format!("{} -U {}", pacman_cmd(p.config), path.display()), p.config),
+                reason: DOWNGRADE_REASON.into(),
+            })
+            .collect(),
+    ))
+}
+
+fn failures_rule(_: &PromptCtx) -> RuleOutput {
+    // --no-pager: output is not a terminal under --capture, and a pager would hide it
+    Ok(Some(vec![
+        Suggestion {
+            cmd: "systemctl --failed --no-pager".to_string(),
+            reason: "list failed units".into(),
+        },
+        Suggestion {
+            cmd: "journalctl -p 3 -xb --no-pager -n 50".to_string(),
+            reason: "show the latest errors from this boot".into(),
+        },
+    ]))
+}
+
+fn keyring_rule(p: &PromptCtx) -> RuleOutput {
+    let config = p.config;
+    let root = privilege(config);
+    let mut cmds = Vec::new();
+    // Refreshing the keyring package needs the network; the pacman-key steps are local
+    if !config.offline {
+        cmds.push(Suggestion {
+            cmd: apply_pkg_flags(format!("{} -Sy archlinux-keyring", pacman_cmd(config)), config),
+            reason: "update the keyring package".into(),
+        });
+    }
+    cmds.push(Suggestion {
+        cmd: format!("{root} pacman-key --init"),
+        reason: "initialize the pacman keyring".into(),
+    });
+    cmds.push(Suggestion {
+        cmd: format!("{root} pacman-key --populate archlinux"),
+        reason: "reload Arch Linux packager keys".into(),
+    });
+    Ok(Some(cmds))
+}
+
+fn sound_rule(_: &PromptCtx) -> RuleOutput {
+    Ok(Some(vec![
+        Suggestion {
+            cmd: "systemctl --user restart pipewire wireplumber".to_string(),
+            reason: "restart audio services".into(),
+        },
+        Suggestion {
+            cmd: "pactl info".to_string(),
+            reason: "inspect pulse server state".into(),
+        },
+    ]))
+}
+
+fn network_rule(p: &PromptCtx) -> RuleOutput {
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{} systemctl restart NetworkManager", privilege(p.config)),
+            reason: "restart network manager".into(),
+        },
+        Suggestion {
+            cmd: "nmcli networking on".to_string(),
+            reason: "enable networking".into(),
+        },
+        Suggestion {
+            cmd: "nmcli -t -f DEVICE,STATE d".to_string(),
+            reason: "list device states".into(),
+        },
+    ]))
+}
+
+fn timezone_rule(p: &PromptCtx) -> RuleOutput {
+    // Zone names are case-sensitive, so take the token from the original prompt
+    let zone = p.prompt.split_whitespace().nth(2).unwrap_or("");
+    if !is_timezone_name(zone) {
+        return Err(AssistError::CommandFailed(format!(
+            "'{zone}' is not an Area/City zone, try {} (`timedatectl list-timezones` lists every zone)",
+            TIMEZONE_EXAMPLES.join(" ")
+        )));
+    }
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{} timedatectl set-timezone {zone}", privilege(p.config)),
+            reason: "set the system timezone".into(),
+        },
+        Suggestion {
+            cmd: "timedatectl status".to_string(),
+            reason: "confirm the new local time".into(),
+        },
+    ]))
+}
+
+fn hostname_rule(p: &PromptCtx) -> RuleOutput {
+    let name = p.lower.split_whitespace().nth(2).unwrap_or("");
+    if !is_hostname(name) {
+        return Err(AssistError::CommandFailed(format!(
+            "'{name}' is not a valid hostname (letters, digits and hyphens, at most 63, \
+             not starting or ending with a hyphen)"
+        )));
+    }
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{} hostnamectl set-hostname {name}", privilege(p.config)),
+            reason: "set the static hostname".into(),
+        },
+        Suggestion {
+            cmd: "hostnamectl status".to_string(),
+            reason: "confirm the new hostname".into(),
+        },
+    ]))
+}
+
+/// A single DNS label: 1-63 ASCII letters, digits or hyphens, no hyphen at either end.
+fn is_hostname(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+fn locale_rule(p: &PromptCtx) -> RuleOutput {
+    // `en_US` and `UTF-8` are case-sensitive in locale names, so read the original prompt
+    let locale = p.prompt.split_whitespace().nth(2).unwrap_or("");
+    if !is_locale_name(locale) {
+        return Err(AssistError::CommandFailed(format!(
+            "'{locale}' is not a locale like en_US.UTF-8 (see /etc/locale.gen for the list)"
+        )));
+    }
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{} localectl set-locale LANG={locale}", privilege(p.config)),
+            reason: "set the system locale".into(),
+        },
+        Suggestion {
+            cmd: "localectl status".to_string(),
+            reason: "confirm the locale and keymap".into(),
+        },
+    ]))
+}
+
+fn keymap_rule(p: &PromptCtx) -> RuleOutput {
+    let keymap = p.prompt.split_whitespace().nth(2).unwrap_or("");
+    let plain = keymap.chars().all(|c| c.is_ascii_alphanumeric() || "-_".contains(c));
+    if keymap.is_empty() || keymap.len() > 32 || !plain {
+        return Err(AssistError::CommandFailed(format!(
+            "'{keymap}' is not a keymap name like uk or de-latin1 (see `localectl list-keymaps`)"
+        )));
+    }
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{} localectl set-keymap {keymap}", privilege(p.config)),
+            reason: "set the console keymap".into(),
+        },
+        Suggestion {
+            cmd: "localectl status".to_string(),
+            reason: "confirm the locale and keymap".into(),
+        },
+    ]))
+}
+
+/// Roughly `xx_XX.charset`, with an optional `@modifier`: `en_US.UTF-8`, `ast_ES.UTF-8`,
+/// `sr_RS.UTF-8@latin`.
+fn is_locale_name(locale: &str) -> bool {
+    let (locale, modifier) = match locale.split_once('@') {
+        Some((locale, modifier)) => (locale, Some(modifier)),
+        None => (locale, None),
+    };
+    let Some((lang, rest)) = locale.split_once('_') else {
+        return false;
+    };
+    let Some((territory, charset)) = rest.split_once('.') else {
+        return false;
+    };
+    (2..=3).contains(&lang.len())
+        && lang.chars().all(|c| c.is_ascii_lowercase())
+        && territory.len() == 2
+        && territory.chars().all(|c| c.is_ascii_uppercase())
+        && !charset.is_empty()
+        && charset.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && modifier.is_none_or(|m| !m.is_empty() && m.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn ntp_rule(p: &PromptCtx) -> RuleOutput {
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{} timedatectl set-ntp true", privilege(p.config)),
+            reason: "enable NTP sync".into(),
+        },
+        Suggestion {
+            cmd: "timedatectl status".to_string(),
+            reason: "show time sync status".into(),
+        },
+    ]))
+}
+
+fn list_installed_rule(p: &PromptCtx) -> RuleOutput {
+    let (flag, reason) = if p.mentions(&["explicit", "manual"]) {
+        ("-Qe", "list explicitly installed packages")
+    } else if ["foreign", "aur"].iter().any(|k| p.lower.split_whitespace().any(|w| w.starts_with(k))) {
+        ("-Qm", "list foreign (AUR and local) packages")
+    } else {
+        ("-Q", "list installed packages")
+    };
+    let filter = p
+        .lower
+        .split_whitespace()
+        .skip_while(|w| !["containing", "matching"].contains(w))
+        .nth(1);
+    match filter {
+        // Pipes are forbidden, so filtering happens inside the verb instead of `| grep`
+        Some(word) => one_command(format!("list-installed {flag} {word}"), "filter pacman's package list"),
+        None => one_command(format!("pacman {flag}"), reason),
+    }
+}
+
+fn disk_rule(p: &PromptCtx) -> RuleOutput {
+    // Read-only and unprivileged; cleanup is only offered when the disk sounds full
+    let mut cmds = vec![
+        Suggestion {
+            cmd: "df -h".to_string(),
+            reason: "show free space per filesystem".into(),
+        },
+        Suggestion {
+            cmd: format!("du -sh {PACMAN_CACHE_DIR} /var/log/journal"),
+            reason: "size of the package cache and the journal".into(),
+        },
+    ];
+    if p.mentions(&["full", "out of space", "no space"]) {
+        cmds.push(Suggestion {
+            cmd: apply_pkg_flags(format!("{} -Sc", pacman_cmd(p.config)), p.config),
+            reason: "drop cached packages that are no longer installed".into(),
+        });
+    }
+    Ok(Some(cmds))
+}
+
+fn wifi_status_rule(_: &PromptCtx) -> RuleOutput {
+    Ok(Some(vec![
+        Suggestion {
+            cmd: "nmcli general status".to_string(),
+            reason: "show network status".into(),
+        },
+        Suggestion {
+            cmd: "nmcli -t -f DEVICE,STATE d".to_string(),
+            reason: "list device connectivity".into(),
+        },
+    ]))
+}
+
+fn bluetooth_rule(p: &PromptCtx) -> RuleOutput {
+    Ok(Some(vec![
+        Suggestion {
+            cmd: format!("{} systemctl restart bluetooth", privilege(p.config)),
+            reason: "restart bluetooth service".into(),
+        },
+        Suggestion {
+            cmd: "bluetoothctl show".to_string(),
+            reason: "show bluetooth adapter state".into(),
+        },
+    ]))
+}
+
+fn ssh_key_rule(p: &PromptCtx) -> RuleOutput {
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    let key = home.join(".ssh").join("id_ed25519");
+    if key.exists() {
+        let line = format!(
+            "warning: {} already exists; a new key would replace it (back it up or rename it first)",
+            key.display()
+        );
+        eprintln!("{}", Paint::Yellow.apply(&line, p.config.color, true));
+    }
+    let user = std::env::var("USER").or_else(|_| std::env::var("LOGNAME")).ok();
+    let host = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok());
+    // stdin is closed while commands run, so the key path has to be given up front
+    one_command(
+        format!(
+            "ssh-keygen -t ed25519 -C \"{}\" -f \"{}\"",
+            ssh_key_comment(user.as_deref(), host.as_deref()),
+            key.display()
+        ),
+        "create an ed25519 key pair (asks for a passphrase)",
+    )
+}
+
+/// `user@host` for the key comment, reduced to characters that survive shell-style splitting.
+fn ssh_key_comment(user: Option<&str>, host: Option<&str>) -> String {
+    let clean = |value: Option<&str>, fallback: &str| {
+        let kept: String = value
+            .unwrap_or_default()
+            .trim()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || "-_.".contains(*c))
+            .collect();
+        if kept.is_empty() { fallback.to_string() } else { kept }
+    };
+    format!("{}@{}", clean(user, "user"), clean(host, "localhost"))
+}
+
+const PACMAN_CACHE_DIR: &str = "/var/cache/pacman/pkg";
+
+/// Marks downgrade candidates, which are alternatives: at most one of them gets installed.
+const DOWNGRADE_REASON: &str = "install this cached version";
+
+/// `version-pkgrel` of every cached package file for `pkg`, newest file first.
+fn cached_versions(dir: &Path, pkg: &str) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut found: Vec<(SystemTime, String, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            // name-pkgver-pkgrel-arch.pkg.tar.{zst,xz}; names may contain dashes themselves
+            let stem = file_name
+                .strip_suffix(".pkg.tar.zst")
+                .or_else(|| file_name.strip_suffix(".pkg.tar.xz"))?;
+            let mut parts = stem.rsplitn(4, '-');
+            let (_arch, rel, ver, name) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+            if name != pkg {
+                return None;
+            }
+            let modified = entry.metadata().and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH);
+            Some((modified, format!("{ver}-{rel}"), entry.path()))
+        })
+        .collect();
+    found.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| b.1.cmp(&a.1)));
+    found.into_iter().map(|(_, version, path)| (version, path)).collect()
+}
+
+/// The installed `version-pkgrel` from `pacman -Q`, if the package is installed.
+fn installed_version(pkg: &str) -> Option<String> {
+    let out = Command::new("pacman").args(["-Q", pkg]).stderr(Stdio::null()).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&out.stdout).split_whitespace().nth(1).map(str::to_string)
+}
+
+/// Checks the tracked list and the local pacman db; `--reinstall` skips both.
+fn already_installed(pkg: &str, config: &ExecConfig, installed: &HashSet<String>) -> bool {
+    !config.reinstall && (installed.contains(pkg) || is_installed_locally(pkg))
+}
+
+fn is_installed_locally(pkg: &str) -> bool {
+    Command::new("pacman")
+        .args(["-Q", pkg])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+fn installed_notice(pkg: &str) -> String {
+    format!("echo {pkg} already installed")
+}
+
+fn is_installed_notice(cmd: &str) -> bool {
+    cmd.starts_with("echo ") && cmd.ends_with(" already installed")
+}
+
+/// Executes one already-validated command line. Internal verbs never reach a runner.
+pub trait CommandRunner {
+    fn run(&self, cmd: &str) -> Result<ExitStatus, AssistError>;
+}
+
+/// Spawns real processes. With `capture`, output is buffered and printed back afterwards.
+pub struct ProcessRunner {
+    pub capture: bool,
+    pub json: bool,
+    /// `--pager`: long read-only output goes through `$PAGER` on a terminal
+    pub pager: bool,
+    /// `--sudo-askpass`: exported as `SUDO_ASKPASS` for `sudo -A`
+    pub sudo_askpass: Option<String>,
+}
+
+/// Flag put right after `sudo`/`doas` so a password prompt nobody can answer never blocks a
+/// run: `-A` (ask the askpass program) when one is configured, `-n` (fail at once unless
+/// credentials are cached or NOPASSWD) when there is no terminal to prompt on. doas has no askpass.
+fn privilege_flag(prog: &str, askpass: bool, terminal: bool) -> Option<&'static str> {
+    match prog {
+        "sudo" if askpass => Some("-A"),
+        "sudo" | "doas" if !terminal => Some("-n"),
+        _ => None,
+    }
+}
+
+/// Whether sudo/doas could prompt: they read the password from `/dev/tty`, not stdin, so
+/// `ai - < steps.txt` at a terminal still gets a prompt while cron and CI do not.
+fn password_terminal() -> bool {
+    fs::OpenOptions::new().read(true).write(true).open("/dev/tty").is_ok()
+}
+
+impl CommandRunner for ProcessRunner {
+    fn run(&self, cmd: &str) -> Result<ExitStatus, AssistError> {
+        // `validate` only lets `|` through under --allow-pipes; each segment becomes its own
+        // process with stdout wired to the next one's stdin. No shell is involved.
+        let segments: Vec<&str> = cmd.split('|').map(str::trim).collect();
+        let paged = self.pager
+            && !self.capture
+            && !self.json
+            && io::stdout().is_terminal()
+            && is_informational(segments[0])
+            && program_token(segments[segments.len() - 1]) != "less";
+        let mut children = Vec::new();
+        let mut upstream: Option<std::process::ChildStdout> = None;
+        for (i, segment) in segments.iter().enumerate() {
+            let parts = shell_split(segment).map_err(|e| AssistError::CommandFailed(format!("{cmd} ({e})")))?;
+            let (prog, args) = parts
+                .split_first()
+                .ok_or_else(|| AssistError::CommandFailed(cmd.into()))?;
+
+            // The first process reads the terminal so pacman and helper prompts can be answered;
+            // without one it gets EOF rather than whatever stdin happens to be
+            let input = match upstream.take() {
+                Some(out) => Stdio::from(out),
+                None if io::stdin().is_terminal() => Stdio::inherit(),
+                None => Stdio::null(),
+            };
+            let mut command = Command::new(prog);
+            if let Some(flag) = privilege_flag(prog, self.sudo_askpass.is_some(), password_terminal()) {
+                command.arg(flag);
+            }
+            if let (Some(askpass), "sudo") = (&self.sudo_askpass, prog.as_str()) {
+                command.env("SUDO_ASKPASS", askpass);
+            }
+            command.args(args).stdin(input);
+            let last = i + 1 == segments.len();
+            if !last {
+                command.stdout(Stdio::piped());
+            } else if self.capture {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+            } else if paged {
+                // stderr stays on the terminal so errors are not buried in the pager
+                command.stdout(Stdio::piped());
+            } else if self.json {
+                // Streamed output goes to stderr so stdout stays a single JSON document
+                let stderr = io::stderr()
+                    .as_fd()
+                    .try_clone_to_owned()
+                    .map_err(|e| AssistError::CommandFailed(format!("{cmd} ({e})")))?;
+                command.stdout(Stdio::from(stderr));
+            }
+            let mut child = command.spawn().map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    AssistError::CommandFailed(format!("{prog} not found; install or adjust PATH"))
+                } else {
+                    AssistError::CommandFailed(format!("{cmd} ({e})"))
+                }
+            })?;
+            if !last {
+                upstream = child.stdout.take();
+            }
+            children.push(child);
+        }
+        let wait_err = |e: std::io::Error| AssistError::CommandFailed(format!("{cmd} ({e})"));
+        let mut tail = children.pop().ok_or_else(|| AssistError::CommandFailed(cmd.into()))?;
+
+        // Streaming stays the default so interactive tools (pacman prompts, progress bars) behave
+        if !self.capture && !paged {
+            let status = tail.wait().map_err(wait_err);
+            for mut child in children {
+                let _ = child.wait();
+            }
+            return status;
+        }
+        if paged {
+            let output = tail.wait_with_output().map_err(wait_err)?;
+            for mut child in children {
+                let _ = child.wait();
+            }
+            page(&output.stdout);
+            return Ok(output.status);
+        }
+
+        let output = tail.wait_with_output().map_err(wait_err)?;
+        for mut child in children {
+            let _ = child.wait();
+        }
+        print_transcript(cmd, &output, self.json);
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if !output.status.success() && !stderr.is_empty() {
+            return Err(AssistError::from_status(
+                format!("{cmd} exited with {}: {stderr}", output.status),
+                output.status,
+            ));
+        }
+        Ok(output.status)
+    }
+}
+
+pub fn run(cmd: &str, state: &mut AppState, runner: &dyn CommandRunner) -> Result<(), AssistError> {
+    // Keep stdout clean for --json consumers
+    if !state.config.json {
+        println!("{cmd}");
+    }
+
+    if state.config.dry_run {
+        return Ok(());
+    }
+
+    // `launch` is an internal verb, not a program on PATH
+    if let Some(app) = cmd.strip_prefix("launch ") {
+        launch(app.trim(), &state.config)?;
+        record_history(cmd, "0", state);
+        return Ok(());
+    }
+    if cmd == "remove-orphans" {
+        return remove_orphans(state, runner);
+    }
+    if let Some(args) = cmd.strip_prefix("list-installed ") {
+        list_installed(args)?;
+        record_history(cmd, "0", state);
+        return Ok(());
+    }
+    if let Some(name) = cmd.strip_prefix("kill-process ") {
+        return kill_process(name.trim(), state, runner);
+    }
+    if cmd == "unlock-pacman-db" {
+        unlock_pacman_db(&state.config)?;
+        record_history(cmd, "0", state);
+        return Ok(());
+    }
+    if cmd == "battery-status" {
+        battery_status()?;
+        record_history(cmd, "0", state);
+        return Ok(());
+    }
+
+    let first = cmd.split('|').next().unwrap_or(cmd);
+    if is_prompting_pkg_op(first) && !first.contains("--noconfirm") && !io::stdin().is_terminal() {
+        let line = format!(
+            "warning: {} asks for confirmation but no terminal is attached; it may hang or abort \
+             (pass --yes or --non-interactive)",
+            program_token(first)
+        );
+        eprintln!("{}", Paint::Yellow.apply(&line, state.config.color, true));
+    }
+    let wrapper = cmd.split_whitespace().next().unwrap_or("");
+    let askpass = wrapper == "sudo" && state.config.sudo_askpass.is_some();
+    if is_privilege_wrapper(wrapper) && !askpass && !password_terminal() {
+        let line = format!(
+            "warning: no terminal for a password prompt; running {wrapper} -n, which fails unless \
+             credentials are cached or passwordless (see --sudo-askpass)"
+        );
+        eprintln!("{}", Paint::Yellow.apply(&line, state.config.color, true));
+    }
+
+    let outcome = runner.run(cmd);
+    let code = match &outcome {
+        Ok(status) => status.code().map_or_else(|| "signal".to_string(), |c| c.to_string()),
+        Err(AssistError::Exited { code, .. }) => code.to_string(),
+        Err(_) => "error".to_string(),
+    };
+    record_history(cmd, &code, state);
+    let status = outcome?;
+
+    if state.config.verbose {
+        let line = format!("-> {cmd} exited with {status}");
+        eprintln!("{}", Paint::Yellow.apply(&line, state.config.color, true));
+    }
+
+    if !status.success() {
+        return Err(AssistError::from_status(format!("{cmd} exited with {status}"), status));
+    }
+
+    update_installed_state(cmd, &status, state);
+
+    Ok(())
+}
+
+fn history_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("state"),
+    };
+    Some(base.join("arch-assist").join("history.log"))
+}
+
+/// Appends `timestamp<TAB>exit<TAB>prompt<TAB>command`. Failures only warn: the audit
+/// trail must never stop a command that already ran.
+fn record_history(cmd: &str, exit: &str, state: &AppState) {
+    if !state.config.history {
+        return;
+    }
+    let Some(path) = history_path() else {
+        return;
+    };
+    let clean = |s: &str| s.replace(['\t', '\n', '\r'], " ");
+    let prompt = state.prompt.as_deref().map_or_else(|| "-".to_string(), clean);
+    let line = format!("{}\t{exit}\t{prompt}\t{}\n", iso8601_utc(unix_now()), clean(cmd));
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = result {
+        if state.config.verbose {
+            eprintln!("-> could not write history to {} ({e})", path.display());
+        }
+    }
+}
+
+struct HistoryEntry {
+    timestamp: String,
+    exit: String,
+    prompt: String,
+    cmd: String,
+}
+
+fn parse_history_line(line: &str) -> Option<HistoryEntry> {
+    let mut fields = line.splitn(4, '\t');
+    Some(HistoryEntry {
+        timestamp: fields.next()?.to_string(),
+        exit: fields.next()?.to_string(),
+        prompt: fields.next()?.to_string(),
+        cmd: fields.next()?.to_string(),
+    })
+}
+
+fn load_history() -> Vec<HistoryEntry> {
+    history_path()
+        .and_then(|p| fs::read_to_string(p).ok())
+        .map(|data| data.lines().filter_map(parse_history_line).collect())
+        .unwrap_or_default()
+}
+
+pub fn handle_history(
+    replay: Option<usize>,
+    last: bool,
+    state: &mut AppState,
+    runner: &dyn CommandRunner,
+) -> Result<(), AssistError> {
+    let entries = load_history();
+    let index = match (replay, last) {
+        (Some(n), _) => Some(n),
+        (None, true) => Some(entries.len()),
+        (None, false) => None,
+    };
+
+    let Some(n) = index else {
+        if entries.is_empty() {
+            println!("No history yet");
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            println!("{:>4}  {}  exit {:<6}  {}", i + 1, entry.timestamp, entry.exit, entry.cmd);
+            if entry.prompt != "-" {
+                println!("      # {}", entry.prompt);
+            }
+        }
+        return Ok(());
+    };
+
+    let entry = n
+        .checked_sub(1)
+        .and_then(|i| entries.get(i))
+        .ok_or_else(|| AssistError::CommandFailed(format!("no history entry {n} ({} recorded)", entries.len())))?;
+    if entry.prompt != "-" {
+        state.prompt = Some(entry.prompt.clone());
+    }
+    // Replays go through the same gate as fresh commands; the allowlist may have changed since
+    validate(&entry.cmd, &state.config)?;
+    ensure_offline_ok(
+        &Suggestion {
+            cmd: entry.cmd.clone(),
+            reason: "history replay".into(),
+        },
+        &state.config,
+    )?;
+    run(&entry.cmd, state, runner)
+}
+
+/// Formats Unix seconds as `YYYY-MM-DDTHH:MM:SSZ` (days-to-civil, proleptic Gregorian).
+fn iso8601_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Read-only commands whose output can run long: logs, listings and status reports.
+fn is_informational(cmd: &str) -> bool {
+    let (program, args) = split_program(cmd);
+    match program {
+        "journalctl" | "df" | "du" | "lsblk" | "checkupdates" => true,
+        "systemctl" => args
+            .iter()
+            .any(|a| ["--failed", "status", "list-units", "list-unit-files"].contains(a)),
+        "pacman" => args
+            .first()
+            .is_some_and(|op| ["-Q", "-F", "-Ss", "-Si"].iter().any(|p| op.starts_with(p))),
+        _ => false,
+    }
+}
+
+/// `--pager`: shows `text` through `$PAGER` (default `less -R`). Without a usable pager the
+/// text is printed as is.
+fn page(text: &[u8]) {
+    if text.is_empty() {
+        return;
+    }
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or_else(|| "less -R".to_string());
+    let spawned = shell_split(&pager).ok().and_then(|argv| {
+        let (prog, args) = argv.split_first()?;
+        Command::new(prog).args(args).stdin(Stdio::piped()).spawn().ok()
+    });
+    let Some(mut child) = spawned else {
+        eprintln!("warning: pager `{pager}` not available; printing the output directly");
+        let _ = io::stdout().write_all(text);
+        return;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // Quitting the pager early closes the pipe; that is not an error
+        let _ = stdin.write_all(text);
+    }
+    let _ = child.wait();
+}
+
+/// Prints buffered output from `--capture`. Goes to stderr under `--json` to keep stdout parseable.
+fn print_transcript(cmd: &str, output: &std::process::Output, json: bool) {
+    let mut text = format!("----- {cmd} -----\n");
+    for (label, bytes) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        if bytes.is_empty() {
+            continue;
+        }
+        text.push_str(&format!("--- {label} ---\n"));
+        let body = String::from_utf8_lossy(bytes);
+        text.push_str(&body);
+        if !body.ends_with('\n') {
+            text.push('\n');
+        }
+    }
+    text.push_str(&format!("----- exit: {} -----", output.status));
+    if json {
+        eprintln!("{text}");
+    } else {
+        println!("{text}");
+    }
+}
+
+fn launch(app: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    let argv = resolve_launch_target(app).ok_or_else(|| {
+        AssistError::CommandFailed(format!(
+            "launch {app}: no executable on PATH or .desktop entry found"
+        ))
+    })?;
+    let (prog, args) = argv
+        .split_first()
+        .ok_or_else(|| AssistError::CommandFailed(format!("launch {app}: empty Exec line")))?;
+
+    // Detach from the terminal; GUI apps outlive this process
+    Command::new(prog)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| AssistError::CommandFailed(format!("launch {app} ({e})")))?;
+
+    if config.verbose {
+        let line = format!("-> launched {app} via {}", argv.join(" "));
+        eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+    }
+    Ok(())
+}
+
+const PACMAN_DB_LOCK: &str = "/var/lib/pacman/db.lck";
+
+/// Expands the `unlock-pacman-db` verb. Deleting the lock under a live pacman corrupts the
+/// database, so this refuses while pacman runs and always asks, even with `--yes`.
+fn unlock_pacman_db(config: &ExecConfig) -> Result<(), AssistError> {
+    let running = Command::new("pgrep")
+        .args(["-x", "pacman"])
+        .output()
+        .map_err(|e| AssistError::CommandFailed(format!("pgrep ({e})")))?;
+    if running.status.success() {
+        let pids = String::from_utf8_lossy(&running.stdout)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(AssistError::CommandFailed(format!(
+            "pacman is still running (pid {pids}); let it finish instead of removing the lock"
+        )));
+    }
+    if !Path::new(PACMAN_DB_LOCK).exists() {
+        println!("No {PACMAN_DB_LOCK}; the database is not locked");
+        return Ok(());
+    }
+
+    let answer = ask(&format!("No pacman process found. Remove {PACMAN_DB_LOCK}? [y/N] "), config)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes" | "YES") {
+        return Err(AssistError::CommandFailed(format!("left {PACMAN_DB_LOCK} in place")));
+    }
+    if config.no_sudo {
+        return fs::remove_file(PACMAN_DB_LOCK)
+            .map_err(|e| AssistError::CommandFailed(format!("remove {PACMAN_DB_LOCK} ({e})")));
+    }
+    // A fixed argv spawned here, never a user-supplied rm
+    let status = Command::new(privilege(config))
+        .args(["rm", "-f", PACMAN_DB_LOCK])
+        .status()
+        .map_err(|e| AssistError::CommandFailed(format!("remove {PACMAN_DB_LOCK} ({e})")))?;
+    if !status.success() {
+        return Err(AssistError::CommandFailed(format!("remove {PACMAN_DB_LOCK} exited with {status}")));
+    }
+    Ok(())
+}
+
+/// "enable firewall", "turn on the firewall", "open port 22". "firewall" has to be a word of its
+/// own, so packages and units like firewalld or firewall-cmd stay with install/remove/logs.
+fn is_firewall_prompt(p: &PromptCtx) -> bool {
+    if p.mentions(&["open port", "allow port"]) {
+        return true;
+    }
+    const VERBS: [&str; 6] = ["enable", "disable", "start", "stop", "activate", "deactivate"];
+    p.lower.split_whitespace().any(|w| w == "firewall")
+        && (VERBS.contains(&p.first()) || p.mentions(&["turn on", "turn off", "switch on", "switch off", "set up"]))
+}
+
+/// "enable firewall" / "open port 22": uses ufw when installed, firewalld otherwise, and installs
+/// ufw through the usual package resolution when neither is present.
+fn firewall_suggestions(
+    lower: &str,
+    config: &ExecConfig,
+    installed: &HashSet<String>,
+    resolver: &dyn PackageResolver,
+) -> Result<Vec<Suggestion>, AssistError> {
+    let has = |pkg: &str| installed.contains(pkg) || is_installed_locally(pkg);
+    let port = lower
+        .split_whitespace()
+        .skip_while(|w| *w != "port")
+        .nth(1)
+        .map(|w| w.parse::<u16>().ok().filter(|p| *p != 0).ok_or(w));
+    let port = match port {
+        Some(Err(word)) => {
+            return Err(AssistError::CommandFailed(format!("'{word}' is not a port number (1-65535)")));
+        }
+        Some(Ok(port)) => Some(port),
+        None => None,
+    };
+    let enable = port.is_none() || lower.contains("enable") || lower.contains("turn on");
+    let sudo = privilege(config);
+    let mut out = Vec::new();
+
+    if !has("ufw") && has("firewalld") {
+        if enable {
+            out.push(Suggestion {
+                cmd: format!("{sudo} systemctl enable --now firewalld"),
+                reason: "start firewalld now and at boot".into(),
+            });
+        }
+        if let Some(port) = port {
+            out.push(Suggestion {
+                cmd: format!("{sudo} firewall-cmd --add-port={port}/tcp --permanent"),
+                reason: format!("open TCP port {port} in the permanent config").into(),
+            });
+            out.push(Suggestion {
+                cmd: format!("{sudo} firewall-cmd --reload"),
+                reason: "apply the permanent config".into(),
+            });
+        }
+        return Ok(out);
+    }
+
+    if !has("ufw") {
+        if let Some(install) = build_install_command("ufw", "-S --needed", config, resolver)? {
+            out.push(Suggestion {
+                cmd: install,
+                reason: "no firewall installed; ufw is the simplest".into(),
+            });
+        }
+    }
+    // Allow before enabling so enabling over SSH does not cut off the open session
+    if let Some(port) = port {
+        out.push(Suggestion {
+            cmd: format!("{sudo} ufw allow {port}"),
+            reason: format!("open port {port}").into(),
+        });
+    }
+    if enable {
+        out.push(Suggestion {
+            cmd: format!("{sudo} ufw enable"),
+            reason: "turn the firewall on (persists across reboots)".into(),
+        });
+    }
+    Ok(out)
+}
+
+const TIMEZONE_EXAMPLES: [&str; 4] = ["Europe/London", "America/New_York", "Asia/Tokyo", "UTC"];
+
+/// Rough tzdata name check: `UTC`, or a capitalised area followed by one or more `/City` parts.
+fn is_timezone_name(zone: &str) -> bool {
+    if zone == "UTC" {
+        return true;
+    }
+    let mut parts = zone.split('/');
+    let area = parts.next().unwrap_or("");
+    let cities: Vec<&str> = parts.collect();
+    area.starts_with(|c: char| c.is_ascii_uppercase())
+        && area.chars().all(|c| c.is_ascii_alphabetic() || c == '_')
+        && !cities.is_empty()
+        && cities.iter().all(|city| {
+            !city.is_empty() && city.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c))
+        })
+}
+
+/// Expands `list-installed <-Q|-Qe|-Qm> <word>`: runs the query and prints only the lines
+/// containing `word`, case-insensitively.
+fn list_installed(args: &str) -> Result<(), AssistError> {
+    let (flag, word) = args
+        .trim()
+        .split_once(' ')
+        .filter(|(flag, _)| ["-Q", "-Qe", "-Qm"].contains(flag))
+        .ok_or_else(|| {
+            AssistError::CommandFailed(format!("list-installed: expected -Q|-Qe|-Qm and a word, got '{args}'"))
+        })?;
+    let output = Command::new("pacman")
+        .arg(flag)
+        .output()
+        .map_err(|e| AssistError::CommandFailed(format!("pacman {flag} ({e})")))?;
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let matches = filter_lines(&listing, word.trim());
+    if matches.is_empty() {
+        println!("No installed packages containing '{}'", word.trim());
+    }
+    for line in matches {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+fn filter_lines<'a>(text: &'a str, word: &str) -> Vec<&'a str> {
+    let word = word.to_lowercase();
+    text.lines().filter(|line| line.to_lowercase().contains(&word)).collect()
+}
+
+/// Expands the `battery-status` verb: `upower -e` lists the power devices, and each battery
+/// among them is queried with `upower -i` and summarized in one line.
+fn battery_status() -> Result<(), AssistError> {
+    let upower = |args: &[&str]| {
+        Command::new("upower")
+            .args(args)
+            .env("LC_ALL", "C")
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).into_owned())
+            .map_err(|e| AssistError::CommandFailed(format!("upower {} ({e})", args.join(" "))))
+    };
+    let devices = upower(&["-e"])?;
+    let batteries = battery_devices(&devices);
+    if batteries.is_empty() {
+        println!("No battery found (desktop, or upower cannot see it)");
+        return Ok(());
+    }
+    for device in batteries {
+        let info = upower(&["-i", device])?;
+        let name = device.rsplit('_').next().unwrap_or(device);
+        println!("{}", battery_summary(name, &info));
+    }
+    Ok(())
+}
+
+/// Battery object paths from `upower -e`, e.g. `/org/freedesktop/UPower/devices/battery_BAT0`.
+fn battery_devices(listing: &str) -> Vec<&str> {
+    listing
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.rsplit('/').next().is_some_and(|dev| dev.starts_with("battery_BAT")))
+        .collect()
+}
+
+/// "BAT0: 87% (discharging, 3.2 hours to empty)" from `upower -i` output.
+fn battery_summary(name: &str, info: &str) -> String {
+    let field = |key: &str| {
+        info.lines()
+            .find_map(|line| line.trim().strip_prefix(key)?.trim_start().strip_prefix(':'))
+            .map(str::trim)
+    };
+    let mut details = vec![field("state").unwrap_or("unknown state").to_string()];
+    for key in ["time to empty", "time to full"] {
+        if let Some(time) = field(key) {
+            details.push(format!("{time} {}", key.trim_start_matches("time ")));
+        }
+    }
+    format!("{name}: {} ({})", field("percentage").unwrap_or("?%"), details.join(", "))
+}
+
+/// Processes the system cannot lose; `kill-process` refuses them by name.
+const CRITICAL_PROCESSES: [&str; 4] = ["systemd", "init", "systemd-journald", "dbus-daemon"];
+
+/// Expands the `kill-process <name>` verb: exact-name `pgrep`, never PID 1, this process or
+/// the shell that started it, and always a confirmation, even with `--yes`.
+fn kill_process(name: &str, state: &mut AppState, runner: &dyn CommandRunner) -> Result<(), AssistError> {
+    // pgrep takes a regex; a plain name keeps `.*` and friends from matching everything
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        return Err(AssistError::CommandFailed(format!("kill: '{name}' is not a plain process name")));
+    }
+    if CRITICAL_PROCESSES.contains(&name) {
+        return Err(AssistError::CommandFailed(format!(
+            "refusing to kill {name}: the system depends on it"
+        )));
+    }
+    let found = Command::new("pgrep")
+        .args(["-x", name])
+        .output()
+        .map_err(|e| AssistError::CommandFailed(format!("pgrep ({e})")))?;
+    let own = std::process::id();
+    let pids: Vec<u32> = String::from_utf8_lossy(&found.stdout)
+        .split_whitespace()
+        .filter_map(|pid| pid.parse().ok())
+        .filter(|pid| *pid != own)
+        .collect();
+    if pids.is_empty() {
+        println!("No running process named {name}");
+        return Ok(());
+    }
+    if pids.contains(&1) || pids.contains(&std::os::unix::process::parent_id()) {
+        return Err(AssistError::CommandFailed(format!(
+            "refusing to kill {name}: it is PID 1 or the shell running arch-assist"
+        )));
+    }
+
+    let list = pids.iter().map(u32::to_string).collect::<Vec<_>>().join(" ");
+    let answer = ask(&format!("Kill {name} (pid {list})? [y/N] "), &state.config)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes" | "YES") {
+        return Err(AssistError::CommandFailed(format!("left {name} running")));
+    }
+    // `kill` is not on the allowlist; these PIDs came from pgrep and passed the checks above
+    run(&format!("kill {list}"), state, runner)
+}
+
+/// Expands the `remove-orphans` verb: pipes are forbidden, so the orphan list is captured
+/// here and handed to `pacman -Rns` as plain arguments.
+fn remove_orphans(state: &mut AppState, runner: &dyn CommandRunner) -> Result<(), AssistError> {
+    let output = Command::new("pacman")
+        .arg("-Qtdq")
+        .output()
+        .map_err(|e| AssistError::CommandFailed(format!("pacman -Qtdq ({e})")))?;
+    // pacman exits 1 with empty output when there is nothing to report
+    let orphans: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(|p| p.to_string())
+        .collect();
+    if orphans.is_empty() {
+        if !state.config.json {
+            println!("No orphaned packages");
+        }
+        return Ok(());
+    }
+
+    let removal = apply_pkg_flags(
+        format!("{} -Rns {}", pacman_cmd(&state.config), orphans.join(" ")),
+        &state.config,
+    );
+    validate(&removal, &state.config)?;
+    run(&removal, state, runner)
+}
+
+fn resolve_launch_target(app: &str) -> Option<Vec<String>> {
+    if let Some(path) = find_in_path(app) {
+        return Some(vec![path.to_string_lossy().into_owned()]);
+    }
+    if let Some(id) = installed_flatpak(app) {
+        return Some(vec!["flatpak".to_string(), "run".to_string(), id]);
+    }
+    find_desktop_exec(app)
+}
+
+fn find_in_path(prog: &str) -> Option<PathBuf> {
+    if prog.is_empty() || prog.contains('/') {
+        return None;
+    }
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(prog))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn application_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => dirs.push(PathBuf::from(dir)),
+        _ => {
+            if let Some(home) = std::env::var_os("HOME") {
+                dirs.push(PathBuf::from(home).join(".local/share"));
+            }
+        }
+    }
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+    dirs.extend(data_dirs.split(':').map(PathBuf::from));
+    dirs.push(PathBuf::from("/var/lib/flatpak/exports/share"));
+    dirs.into_iter().map(|d| d.join("applications")).collect()
+}
+
+fn find_desktop_exec(app: &str) -> Option<Vec<String>> {
+    let wanted = app.to_lowercase();
+    let dirs = application_dirs();
+
+    // Exact file name first, then reverse-DNS ids (org.videolan.VLC) or a matching Name=
+    for dir in &dirs {
+        if let Some(exec) = desktop_exec_line(&dir.join(format!("{app}.desktop"))) {
+            return Some(exec);
+        }
+    }
+    for dir in &dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(stem) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".desktop"))
+            else {
+                continue;
+            };
+            let stem = stem.to_lowercase();
+            if stem.ends_with(&format!(".{wanted}")) || desktop_name_matches(&path, &wanted) {
+                if let Some(exec) = desktop_exec_line(&path) {
+                    return Some(exec);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn desktop_name_matches(path: &Path, wanted: &str) -> bool {
+    fs::read_to_string(path)
+        .map(|data| {
+            data.lines()
+                .filter_map(|l| l.strip_prefix("Name="))
+                .any(|name| name.trim().to_lowercase() == wanted)
+        })
+        .unwrap_or(false)
+}
+
+fn desktop_exec_line(path: &Path) -> Option<Vec<String>> {
+    let data = fs::read_to_string(path).ok()?;
+    let mut in_entry = false;
+    for line in data.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_entry = line == "[Desktop Entry]";
+            continue;
+        }
+        if !in_entry {
+            continue;
+        }
+        if let Some(exec) = line.strip_prefix("Exec=") {
+            // Drop field codes such as %U / %f that only make sense for file managers
+            let argv: Vec<String> = shell_split(exec)
+                .ok()?
+                .into_iter()
+                .filter(|arg| !(arg.starts_with('%') && arg.len() == 2))
+                .collect();
+            return if argv.is_empty() { None } else { Some(argv) };
+        }
+    }
+    None
+}
+
+const ALLOWED_PROGRAMS: &[&str] = &[
+    "sudo",
+    "doas",
+    "pacman",
+    "paru",
+    "systemctl",
+    "nmcli",
+    "pactl",
+    "bluetoothctl",
+    "journalctl",
+    "timedatectl",
+    "echo",
+    "launch",
+    "checkupdates",
+    "remove-orphans",
+    "pacman-key",
+    "unlock-pacman-db",
+    "kill-process",
+    "list-installed",
+    "pgrep",
+    "mkinitcpio",
+    "reflector",
+    "df",
+    "du",
+    "flatpak",
+    "ufw",
+    "firewall-cmd",
+    "snapper",
+    "lsblk",
+    "udisksctl",
+    "ssh-keygen",
+    "hostnamectl",
+    "localectl",
+    "upower",
+    "battery-status",
+    "pkgfile",
+];
+
+/// Read-only filters that may follow a `|` under `--allow-pipes`.
+const PIPE_FILTERS: &[&str] = &["grep", "tail", "head", "less"];
+
+pub fn validate(cmd: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    let result = if config.allow_pipes && cmd.contains('|') && !cmd.contains("||") {
+        validate_pipeline(cmd, config)
+    } else {
+        validate_segment(cmd, config)
+    };
+    if let Err(e) = &result {
+        log_check(cmd, &format!("blocked ({e})"), config);
+    }
+    result
+}
+
+/// `--verbose`: one line per safety check that passed, so an audit can see what ran.
+fn log_check(cmd: &str, check: &str, config: &ExecConfig) {
+    if config.verbose {
+        let line = format!("-> validate `{cmd}`: {check}");
+        eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+    }
+}
+
+/// `--allow-pipes`: the first segment passes the normal checks, every later one must be a
+/// plain read-only filter. Internal verbs are not processes, so they cannot be piped.
+fn validate_pipeline(cmd: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    let mut segments = cmd.split('|').map(str::trim);
+    let head = segments.next().unwrap_or_default();
+    let verb = program_token(head);
+    const VERBS: [&str; 6] = [
+        "launch",
+        "remove-orphans",
+        "unlock-pacman-db",
+        "kill-process",
+        "list-installed",
+        "battery-status",
+    ];
+    if VERBS.contains(&verb) {
+        return Err(blocked(&format!("internal verb `{verb}` cannot be piped"), cmd));
+    }
+    validate_segment(head, config)?;
+    for segment in segments {
+        scan_forbidden(segment)?;
+        let program = segment.split_whitespace().next().unwrap_or_default();
+        check_user_forbidden(program, cmd, config)?;
+        if !PIPE_FILTERS.contains(&program) {
+            return Err(blocked(
+                &format!("`{program}` is not a read-only filter ({})", PIPE_FILTERS.join(", ")),
+                cmd,
+            ));
+        }
+        log_check(cmd, &format!("piped `{program}` is a read-only filter"), config);
+    }
+    Ok(())
+}
+
+fn validate_segment(cmd: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    scan_forbidden(cmd)?;
+    log_check(cmd, "forbidden-operator and destructive-program scan passed", config);
+
+    // Minimal allowlist on the leading token; privilege wrappers are looked through
+    // so `sudo <anything>` cannot bypass the list
+    let first = program_token(cmd);
+    check_user_forbidden(first, cmd, config)?;
+    // A custom helper from extra_allowed goes through the extra_allowed path below
+    let allowed_program =
+        ALLOWED_PROGRAMS.contains(&first) || (is_aur_helper(first) && aur_helper(config).is_ok_and(|h| h == first));
+    // Raw `kill` is only reachable through extra_allowed; the kill-process verb runs it itself
+    if first == "kill" && !kill_targets_are_safe(&split_program(cmd).1) {
+        return Err(blocked("refusing to signal PID 0, PID 1 or a process group", cmd));
+    }
+    if first == "kill" {
+        log_check(cmd, "kill targets only plain PIDs above 1", config);
+    }
+    // udisksctl acts on whatever -b names; only whole disks and partitions pass
+    if first == "udisksctl" {
+        let args = split_program(cmd).1;
+        let device = args.iter().skip_while(|a| !["-b", "--block-device"].contains(*a)).nth(1);
+        if device.is_some_and(|dev| !is_block_device(dev)) {
+            return Err(blocked("not a disk or partition device", cmd));
+        }
+        log_check(cmd, "udisksctl device is a disk or partition", config);
+    }
+    if !allowed_program {
+        if !config.extra_allowed.iter().any(|p| p == first) {
+            return Err(blocked(&format!("program `{first}` not in allowlist"), cmd));
+        }
+        if config.verbose {
+            let line = format!("-> {first} allowed only by extra_allowed in config.toml");
+            eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+        }
+    } else {
+        log_check(cmd, &format!("leading token `{first}` is on the allowlist"), config);
+    }
+
+    if config.strict_distro && is_arch_specific(cmd, config) && !on_arch_based_system() {
+        return Err(blocked("not an Arch-based system (--strict-distro)", cmd));
+    }
+    if config.strict_distro {
+        log_check(cmd, "distro check passed (--strict-distro)", config);
+    }
+
+    Ok(())
+}
+
+/// `kill 1` takes down init, `kill 0` signals our own process group and a negative PID a whole
+/// group (`-1`: every process the user owns). After the signal (`-9`, `-KILL`, `-s KILL`) and
+/// an optional `--`, every argument has to be a PID above 1.
+fn kill_targets_are_safe(args: &[&str]) -> bool {
+    let mut rest = args;
+    match rest.first() {
+        Some(&("-s" | "-n")) => rest = rest.get(2..).unwrap_or_default(),
+        Some(a) if a.starts_with('-') && *a != "--" => rest = &rest[1..],
+        _ => {}
+    }
+    if rest.first() == Some(&"--") {
+        rest = &rest[1..];
+    }
+    !rest.is_empty() && rest.iter().all(|a| a.parse::<u32>().is_ok_and(|pid| pid > 1))
+}
+
+/// `extra_forbidden` from config.toml beats every allowlist, built-in or user-added.
+fn check_user_forbidden(program: &str, cmd: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    if config.extra_forbidden.iter().any(|p| p == program) {
+        return Err(blocked(&format!("program `{program}` forbidden by extra_forbidden"), cmd));
+    }
+    Ok(())
+}
+
+/// An `Unsafe` error naming the rule that tripped, e.g. "forbidden operator `&&`: a && b".
+fn blocked(rule: &str, cmd: &str) -> AssistError {
+    AssistError::Unsafe(format!("{rule}: {cmd}"))
+}
+
+/// Commands that only make sense on Arch: package management and Arch-only tooling.
+/// Diagnostics like `df` or `systemctl` stay usable everywhere.
+fn is_arch_specific(cmd: &str, config: &ExecConfig) -> bool {
+    const ARCH_PROGRAMS: [&str; 8] = [
+        "pacman",
+        "pacman-key",
+        "checkupdates",
+        "reflector",
+        "mkinitcpio",
+        "remove-orphans",
+        "unlock-pacman-db",
+        "list-installed",
+    ];
+    let program = program_token(cmd);
+    ARCH_PROGRAMS.contains(&program)
+        || is_aur_helper(program)
+        || config.aur_helper.as_deref() == Some(program)
+}
+
+/// Whether `/etc/os-release` names Arch or a derivative, read once per process.
+/// A missing file counts as not Arch.
+pub fn on_arch_based_system() -> bool {
+    static ARCH: OnceLock<bool> = OnceLock::new();
+    *ARCH.get_or_init(|| {
+        ["/etc/os-release", "/usr/lib/os-release"]
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .is_some_and(|text| os_release_is_arch(&text))
+    })
+}
+
+fn os_release_is_arch(text: &str) -> bool {
+    const DERIVATIVES: [&str; 4] = ["manjaro", "endeavouros", "garuda", "cachyos"];
+    let field = |key: &str| {
+        text.lines()
+            .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('='))
+            .map(|v| v.trim().trim_matches(|c| c == '"' || c == '\'').to_lowercase())
+            .unwrap_or_default()
+    };
+    let id = field("ID");
+    id == "arch" || DERIVATIVES.contains(&id.as_str()) || field("ID_LIKE").split_whitespace().any(|w| w == "arch")
+}
+
+fn scan_forbidden(cmd: &str) -> Result<(), AssistError> {
+    const FORBIDDEN: [&str; 9] = ["|", ">", "<", "&&", "||", ";", "`", "$(", " :"];
+    for bad in FORBIDDEN {
+        if cmd.contains(bad) {
+            return Err(blocked(&format!("forbidden operator `{}`", bad.trim()), cmd));
+        }
+    }
+
+    // Token-based so words like `useradd` or `pacman -S mkfs-tools` don't trip it
+    let program = program_token(cmd).rsplit('/').next().unwrap_or("");
+    if program == "dd" || program == "mkfs" || program.starts_with("mkfs.") {
+        return Err(blocked(&format!("destructive program `{program}`"), cmd));
+    }
+    if program == "rm" && is_recursive_force(cmd) {
+        return Err(blocked("recursive forced `rm`", cmd));
+    }
+
+    Ok(())
+}
+
+/// True when the flags of an `rm` call combine recursive and force, in any order or spelling.
+fn is_recursive_force(cmd: &str) -> bool {
+    let mut recursive = false;
+    let mut force = false;
+    for token in cmd.split_whitespace() {
+        if token == "--" {
+            break;
+        }
+        match token {
+            "--recursive" => recursive = true,
+            "--force" => force = true,
+            t if t.starts_with('-') && !t.starts_with("--") => {
+                recursive |= t.contains('r') || t.contains('R');
+                force |= t.contains('f');
+            }
+            _ => {}
+        }
+    }
+    recursive && force
+}
+
+/// The program that will actually run, skipping leading `sudo`/`doas`.
+fn program_token(cmd: &str) -> &str {
+    split_program(cmd).0
+}
+
+fn split_program(cmd: &str) -> (&str, Vec<&str>) {
+    let mut parts = cmd.split_whitespace();
+    let mut first = parts.next().unwrap_or("");
+    while is_privilege_wrapper(first) {
+        first = parts.next().unwrap_or("");
+    }
+    (first, parts.collect())
+}
+
+/// Extra pacman args and `--noconfirm` (under `--yes`/`--non-interactive`) only reach operations
+/// that prompt; queries, file lookups and searches come back unchanged.
+fn apply_pkg_flags(cmd: String, config: &ExecConfig) -> String {
+    if !is_prompting_pkg_op(&cmd) {
+        return cmd;
+    }
+    let cmd = insert_pkg_args(&cmd, &config.extra_pacman_args);
+    if (config.yes || config.non_interactive) && !cmd.contains("--noconfirm") {
+        return format!("{cmd} --noconfirm");
+    }
+    cmd
+}
+
+/// Puts `extra` after the flags already on `cmd` and before its first target, since pacman
+/// stops reading options at the package list. Flags the command already carries are skipped.
+fn insert_pkg_args(cmd: &str, extra: &[String]) -> String {
+    let mut tokens: Vec<&str> = cmd.split_whitespace().collect();
+    let program_at = tokens.iter().position(|t| !is_privilege_wrapper(t)).unwrap_or(0);
+    let targets_at = tokens
+        .iter()
+        .skip(program_at + 1)
+        .position(|t| !t.starts_with('-'))
+        .map_or(tokens.len(), |i| program_at + 1 + i);
+    let missing: Vec<&str> = extra
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| !tokens.contains(arg))
+        .collect();
+    tokens.splice(targets_at..targets_at, missing);
+    tokens.join(" ")
+}
+
+/// `extra_pacman_args` from config.toml followed by `--pacman-arg` values. Only long options
+/// pass (`--needed`, `--overwrite=*`, `--color=always`): no operations, no shell operators,
+/// one token each.
+pub fn extra_pacman_args(file: Vec<String>, cli: Vec<String>) -> Result<Vec<String>, AssistError> {
+    const OPERATIONS: [&str; 7] = ["--database", "--deptest", "--files", "--query", "--remove", "--sync", "--upgrade"];
+    let mut args = file;
+    args.extend(cli);
+    for arg in &args {
+        let name = arg.split('=').next().unwrap_or_default();
+        let reason = if !arg.starts_with("--") || arg.len() == 2 {
+            Some("only long options like --needed are allowed")
+        } else if OPERATIONS.contains(&name) {
+            Some("operations cannot be added")
+        } else if shell_split(arg).map_or(true, |parts| parts.len() != 1) {
+            Some("must be a single argument")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(blocked(&format!("extra pacman argument: {reason}"), arg));
+        }
+        scan_forbidden(arg).map_err(|_| blocked("extra pacman argument: forbidden operator", arg))?;
+    }
+    Ok(args)
+}
+
+fn install_cmd(installer: &str, pkg: &str, config: &ExecConfig, reason: &'static str) -> Suggestion {
+    Suggestion {
+        cmd: apply_pkg_flags(format!("{installer} -S --needed {pkg}"), config),
+        reason: reason.into(),
+    }
+}
+
+enum BatchChoice {
+    Run,
+    Abort,
+    Review,
+}
+
+fn confirm(suggestions: &[Suggestion], config: &ExecConfig) -> Result<BatchChoice, AssistError> {
+    if config.yes {
+        return Ok(BatchChoice::Run);
+    }
+    let sizes = if config.offline {
+        None
+    } else {
+        install_sizes(&install_targets(suggestions))
+    };
+    let prompt = match sizes {
+        Some((download, installed)) => format!(
+            "Run these commands? (download {}, installed {}) [y/N/e(dit)] ",
+            human_size(download),
+            human_size(installed)
+        ),
+        None => "Run these commands? [y/N/e(dit)] ".to_string(),
+    };
+    let input = ask(&prompt, config)?;
+    Ok(match input.trim() {
+        "y" | "Y" | "yes" | "YES" => BatchChoice::Run,
+        "e" | "E" | "edit" => BatchChoice::Review,
+        _ => BatchChoice::Abort,
+    })
+}
+
+/// Package arguments of every `pacman -S` install in the batch; AUR helpers and upgrades are skipped.
+fn install_targets(suggestions: &[Suggestion]) -> Vec<String> {
+    let mut pkgs = Vec::new();
+    for sugg in suggestions {
+        let (program, args) = split_program(&sugg.cmd);
+        let installs = args.iter().any(|a| {
+            a.strip_prefix('-')
+                .is_some_and(|ops| !ops.starts_with('-') && ops.starts_with('S') && ops[1..].chars().all(|c| c == 'y'))
+        });
+        if program != "pacman" || !installs {
+            continue;
+        }
+        pkgs.extend(args.iter().filter(|a| !a.starts_with('-')).map(|a| a.to_string()));
+    }
+    pkgs
+}
+
+/// Total download and installed size of `pkgs` plus the dependencies pacman would pull in.
+/// `None` when there is nothing to install or pacman can't answer (unknown package, no pacman).
+fn install_sizes(pkgs: &[String]) -> Option<(u64, u64)> {
+    if pkgs.is_empty() {
+        return None;
+    }
+    let planned = Command::new("pacman")
+        .args(["-Sp", "--needed", "--print-format", "%n %s"])
+        .args(pkgs)
+        .env("LC_ALL", "C")
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|out| out.status.success())?;
+    let (names, download) = parse_print_format(&String::from_utf8_lossy(&planned.stdout));
+    if names.is_empty() {
+        return None;
+    }
+    let info = Command::new("pacman")
+        .arg("-Si")
+        .args(&names)
+        .env("LC_ALL", "C")
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    Some((download, installed_size_total(&String::from_utf8_lossy(&info.stdout))))
+}
+
+/// `%n %s` lines from `pacman -Sp` -> (names, summed download bytes).
+fn parse_print_format(text: &str) -> (Vec<String>, u64) {
+    let mut names = Vec::new();
+    let mut total = 0;
+    for line in text.lines() {
+        if let Some((name, size)) = line.trim().split_once(' ') {
+            if let Ok(bytes) = size.trim().parse::<u64>() {
+                names.push(name.to_string());
+                total += bytes;
+            }
+        }
+    }
+    (names, total)
+}
+
+/// Sums every "Installed Size : 12.50 MiB" line of `pacman -Si` output.
+fn installed_size_total(text: &str) -> u64 {
+    text.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim() != "Installed Size" {
+                return None;
+            }
+            let (amount, unit) = value.trim().split_once(' ')?;
+            let amount: f64 = amount.parse().ok()?;
+            let scale = match unit.trim() {
+                "B" => 1.0,
+                "KiB" => 1024.0,
+                "MiB" => 1024.0 * 1024.0,
+                "GiB" => 1024.0 * 1024.0 * 1024.0,
+                _ => return None,
+            };
+            Some((amount * scale) as u64)
+        })
+        .sum()
+}
+
+#[derive(Debug, PartialEq)]
+enum StepChoice {
+    Yes,
+    No,
+    All,
+    Quit,
+    Edit,
+}
+
+fn parse_step_choice(input: &str) -> Option<StepChoice> {
+    match input.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Some(StepChoice::Yes),
+        "" | "n" | "no" => Some(StepChoice::No),
+        "a" | "all" => Some(StepChoice::All),
+        "q" | "quit" => Some(StepChoice::Quit),
+        "e" | "edit" => Some(StepChoice::Edit),
+        _ => None,
+    }
+}
+
+fn confirm_step(sugg: &mut Suggestion, config: &ExecConfig) -> Result<StepChoice, AssistError> {
+    loop {
+        let input = ask(
+            &format!("Run `{}`? [y]es / [N]o / [e]dit / [a]ll / [q]uit ", sugg.cmd),
+            config,
+        )?;
+        match parse_step_choice(&input) {
+            Some(StepChoice::Edit) => {
+                let edited = edit_command(&sugg.cmd, config)?;
+                if edited.is_empty() {
+                    continue;
+                }
+                // A bad edit only costs this step; ask again instead of aborting the batch
+                if let Err(e) = validate(&edited, config) {
+                    eprintln!("{e}");
+                    continue;
+                }
+                sugg.cmd = edited;
+                sugg.reason = "edited".into();
+                return Ok(StepChoice::Yes);
+            }
+            Some(choice) => return Ok(choice),
+            None => {}
+        }
+    }
+}
+
+/// Opens the command in `$VISUAL`/`$EDITOR`, or reads a replacement line when neither is set.
+fn edit_command(cmd: &str, config: &ExecConfig) -> Result<String, AssistError> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|v| !v.trim().is_empty());
+    let Some(editor) = editor else {
+        let input = ask(&format!("Edit command [{cmd}]: "), config)?;
+        let trimmed = input.trim();
+        return Ok(if trimmed.is_empty() { cmd.to_string() } else { trimmed.to_string() });
+    };
+
+    let argv = shell_split(&editor).map_err(|e| AssistError::CommandFailed(format!("$EDITOR ({e})")))?;
+    let (prog, args) = argv
+        .split_first()
+        .ok_or_else(|| AssistError::CommandFailed("$EDITOR is empty".into()))?;
+    let path = std::env::temp_dir().join(format!("arch-assist-edit-{}.sh", std::process::id()));
+    fs::write(&path, format!("{cmd}\n")).map_err(|e| AssistError::CommandFailed(format!("edit ({e})")))?;
+    let status = Command::new(prog)
+        .args(args)
+        .arg(&path)
+        .status()
+        .map_err(|e| AssistError::CommandFailed(format!("{editor} ({e})")));
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    if !status?.success() {
+        return Ok(String::new());
+    }
+    let edited = edited.map_err(|e| AssistError::CommandFailed(format!("edit ({e})")))?;
+    Ok(edited
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Prompts on stdout (stderr under `--json`) and reads one line. EOF reads as an empty answer.
+fn ask(prompt: &str, config: &ExecConfig) -> Result<String, AssistError> {
+    if config.json {
+        eprint!("{prompt}");
+    } else {
+        print!("{prompt}");
+        io::stdout()
+            .flush()
+            .map_err(|e| AssistError::CommandFailed(format!("confirm ({e})")))?;
+    }
+    let mut input = String::new();
+    io::stdin()
+        .read_line(&mut input)
+        .map_err(|e| AssistError::CommandFailed(format!("confirm ({e})")))?;
+    Ok(input)
+}
+
+fn ensure_offline_ok(suggestion: &Suggestion, config: &ExecConfig) -> Result<(), AssistError> {
+    if !config.offline {
+        return Ok(());
+    }
+    if needs_network(&suggestion.cmd) {
+        return Err(blocked("offline mode: network command", &suggestion.cmd));
+    }
+    Ok(())
+}
+
+/// Commands `--offline` refuses: network package operations and mirror ranking.
+fn needs_network(cmd: &str) -> bool {
+    let (program, args) = split_program(cmd);
+    is_network_pkg_op(cmd)
+        || program == "reflector"
+        || (program == "flatpak" && args.first().is_some_and(|a| ["install", "update", "search"].contains(a)))
+}
+
+/// Whether a pacman/AUR-helper invocation needs the network (sync installs or any `-Sy*` refresh).
+fn is_network_pkg_op(cmd: &str) -> bool {
+    let (program, args) = split_program(cmd);
+    let helper = is_aur_helper(program);
+    if program != "pacman" && !helper {
+        return false;
+    }
+
+    let ops = pkg_ops(&args);
+    // `-Fy` downloads the file lists that `pacman -F` searches
+    if ops.contains('F') {
+        return ops.contains('y');
+    }
+    // `-U` installs package files; only a URL among them makes pacman download anything
+    if ops.contains('U') {
+        return args.iter().any(|a| a.contains("://"));
+    }
+    // A bare helper upgrades and `paru <term>` installs interactively
+    if helper && ops.is_empty() {
+        return true;
+    }
+    if !ops.contains('S') {
+        return false;
+    }
+    if ops.contains('y') {
+        return true;
+    }
+    // Cache cleanup is local; pacman's sync queries read the local database, helpers' hit the AUR
+    let local_only = if program == "pacman" { "csilgp" } else { "c" };
+    !ops.chars().any(|c| local_only.contains(c))
+}
+
+/// Short operation letters of a pacman-style argument list, with the long forms we emit folded in.
+fn pkg_ops(args: &[&str]) -> String {
+    let mut ops = String::new();
+    for arg in args {
+        match *arg {
+            "--sync" => ops.push('S'),
+            "--refresh" => ops.push('y'),
+            "--upgrade" => ops.push('U'),
+            "--remove" => ops.push('R'),
+            a if a.starts_with('-') && !a.starts_with("--") => ops.push_str(&a[1..]),
+            _ => {}
+        }
+    }
+    ops
+}
+
+/// Whether a pacman/AUR-helper invocation stops for a yes/no answer unless given `--noconfirm`:
+/// installs, removals, upgrades and cache cleanup. Queries (`-Q`, `-F`, `-Ss`, `-Si`) never ask.
+fn is_prompting_pkg_op(cmd: &str) -> bool {
+    let (program, args) = split_program(cmd);
+    let helper = is_aur_helper(program);
+    if program != "pacman" && !helper {
+        return false;
+    }
+    let ops = pkg_ops(&args);
+    // A bare helper upgrades and `paru <term>` shows a selection menu
+    if helper && ops.is_empty() {
+        return true;
+    }
+    if ops.contains('R') || ops.contains('U') {
+        return true;
+    }
+    if !ops.contains('S') || ops.chars().any(|c| "silgp".contains(c)) {
+        return false;
+    }
+    // A bare `-Sy` only refreshes the databases
+    ops.contains('u') || ops.contains('c') || args.iter().any(|a| !a.starts_with('-'))
+}
+
+pub fn load_installed(path: &Path) -> HashSet<String> {
+    if let Ok(data) = fs::read_to_string(path) {
+        return data
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect();
+    }
+    HashSet::new()
+}
+
+fn save_installed(state: &AppState) -> Result<(), AssistError> {
+    let mut pkgs: Vec<_> = state.installed.iter().cloned().collect();
+    pkgs.sort();
+    fs::write(&state.installed_file, pkgs.join("\n"))
+        .map_err(|e| AssistError::CommandFailed(format!("save installed ({e})")))
+}
+
+fn update_installed_state(cmd: &str, status: &std::process::ExitStatus, state: &mut AppState) {
+    if !status.success() {
+        return;
+    }
+    let trimmed = cmd.trim();
+    let parts: Vec<&str> = trimmed.split_whitespace().collect();
+    if parts.is_empty() {
+        return;
+    }
+
+    let mut idx = 0;
+    let first = parts[0];
+    let mut installer = first;
+    if is_privilege_wrapper(first) && parts.len() > 1 {
+        installer = parts[1];
+        idx = 1;
+    }
+
+    if (installer == "pacman" || is_aur_helper(installer)) && parts.len() > idx + 1 {
+        let op = parts[idx + 1];
+        let install = is_install_op(op);
+        if !install && !op.starts_with("-R") {
+            return;
+        }
+        // Every target after the operation; flags (`--needed`, `--noconfirm`) and the value of
+        // `--ignore` are not packages
+        let mut targets = Vec::new();
+        let mut skip_value = false;
+        for part in &parts[idx + 2..] {
+            if std::mem::take(&mut skip_value) {
+                continue;
+            }
+            if ["--ignore", "--ignoregroup"].contains(part) {
+                skip_value = true;
+            } else if !part.starts_with('-') {
+                targets.push(*part);
+            }
+        }
+        if targets.is_empty() {
+            return;
+        }
+        for pkg in targets {
+            if install {
+                state.installed.insert(pkg.to_string());
+            } else {
+                state.installed.remove(pkg);
+            }
+        }
+        let _ = save_installed(state);
+    }
+}
+
+/// Reason for LLM commands that came back without a `# reason` annotation.
+const LLM_REASON: &str = "LLM suggestion";
+
+/// Splits an LLM line of the form `cmd<TAB># reason` (or `cmd  # reason`) into its parts.
+fn split_llm_reason(line: &str) -> (&str, Option<&str>) {
+    let Some(at) = line.find("\t#").or_else(|| line.find(" #")) else {
+        return (line, None);
+    };
+    let reason = line[at..].trim_start().trim_start_matches('#').trim();
+    (line[..at].trim(), (!reason.is_empty()).then_some(reason))
+}
+
+/// Splits LLM content into unique commands plus the reason given for each. Markdown fence lines
+/// (three backticks, optionally tagged `bash`/`sh`) are dropped; backticks around a command are trimmed.
+/// Duplicates are found after collapsing runs of whitespace, but case still counts, as it does for
+/// Linux programs and flags (`pacman -syu` is not `pacman -Syu`).
+/// Shell prompts (`$ `, a root `# `) are stripped; comment lines and prose are dropped.
+fn parse_llm_commands(content: &str) -> (Vec<String>, HashMap<String, String>) {
+    let mut seen = HashSet::new();
+    let mut cmds: Vec<String> = Vec::new();
+    let mut reasons: HashMap<String, String> = HashMap::new();
+    for line in content.lines().filter(|line| !line.trim_start().starts_with("```")) {
+        let Some(line) = strip_shell_prompt(line.trim().trim_matches('`').trim()) else {
+            continue;
+        };
+        let (clean, reason) = split_llm_reason(line);
+        let clean = clean.trim_matches('`').trim();
+        if clean.is_empty() || !looks_like_command(clean) {
+            continue;
+        }
+        if seen.insert(clean.split_whitespace().collect::<Vec<_>>().join(" ")) {
+            cmds.push(clean.to_string());
+            if let Some(reason) = reason {
+                reasons.insert(clean.to_string(), reason.to_string());
+            }
+        }
+    }
+    (cmds, reasons)
+}
+
+/// `$ cmd` becomes `cmd`. A `#` line is a root prompt only when a known program follows it
+/// (`# pacman -Syu`); otherwise it is a comment and yields `None`.
+fn strip_shell_prompt(line: &str) -> Option<&str> {
+    if let Some(rest) = line.strip_prefix("$ ") {
+        return Some(rest.trim_start());
+    }
+    let Some(rest) = line.strip_prefix('#') else {
+        return Some(line);
+    };
+    let rest = rest.trim_start();
+    let program = rest.split_whitespace().next().unwrap_or("");
+    (ALLOWED_PROGRAMS.contains(&program) || is_aur_helper(program)).then_some(rest)
+}
+
+/// Prose ("Here's how:", "First, update the system") starts with something that cannot be a
+/// program name or ends in a colon.
+fn looks_like_command(line: &str) -> bool {
+    let program = line.split_whitespace().next().unwrap_or("");
+    program.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '/')
+        && program.chars().all(|c| c.is_ascii_alphanumeric() || "._+-/".contains(c))
+        && !line.ends_with(':')
+}
+
+/// Commands from the LLM, each with the reason it gave (if any).
+fn llm_translate(
+    prompt: &str,
+    config: &ExecConfig,
+    installed: &HashSet<String>,
+    resolver: &dyn PackageResolver,
+) -> Result<Vec<(String, Option<String>)>, AssistError> {
+    if !llm_reachable(config) {
+        return Err(AssistError::CommandFailed(
+            "offline mode: LLM suggestions disabled".into(),
+        ));
+    }
+
+    let client = http_client(config)?;
+    let installed_list = if installed.is_empty() {
+        "none".to_string()
+    } else {
+        installed
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let system_prompt = build_system_prompt(&installed_list, config);
+
+    let model = llm_model(config);
+    if config.verbose {
+        eprintln!("LLM model: {model} ({})", config.backend.name());
+    }
+    let cache_key = llm_cache_key(&model, &system_prompt, prompt);
+    let cached = load_llm_cache(&cache_key, config.llm_cache_ttl);
+    let from_cache = cached.is_some();
+    let content_raw = match cached {
+        Some(content) => content,
+        None => llm_chat(&client, &system_prompt, prompt, config)?,
+    };
+    if from_cache && config.verbose {
+        eprintln!("LLM response served from cache");
+    }
+
+    if config.verbose {
+        eprintln!("LLM raw content: {}", content_raw);
+    }
+
+    let content = content_raw.trim();
+    if content.is_empty() {
+        return Err(AssistError::Network("LLM returned only whitespace".into()));
+    }
+
+    let (cmds, reasons) = parse_llm_commands(content);
+    if cmds.is_empty() {
+        return Err(AssistError::Network("LLM returned an empty command list".into()));
+    }
+
+    let mut safe_cmds = Vec::new();
+    for cmd in cmds {
+        match validate(&cmd, config) {
+            Ok(()) => safe_cmds.push(cmd),
+            Err(e) if config.verbose => {
+                let line = format!("-> dropped LLM command `{cmd}`: {e}");
+                eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+            }
+            Err(_) => {}
+        }
+    }
+
+    if safe_cmds.is_empty() {
+        return Err(AssistError::Network("LLM produced no safe commands (blocked or unsupported)".into()));
+    }
+    if !from_cache {
+        store_llm_cache(&cache_key, content, config.llm_cache_ttl);
+    }
+
+    let adjusted = adjust_commands_for_intent(safe_cmds, prompt, config);
+    let launch_app = extract_app_name_from_install(&adjusted);
+
+    let mut remapped: Vec<(String, Option<String>)> = Vec::new();
+    for cmd in adjusted {
+        // adjust_commands_for_intent may have wrapped the command in `launch`
+        let reason = reasons
+            .get(&cmd)
+            .or_else(|| cmd.strip_prefix("launch ").and_then(|inner| reasons.get(inner)))
+            .cloned();
+        for rewritten in rewrite_install_with_resolution(cmd, config, resolver)? {
+            remapped.push((rewritten, reason.clone()));
+        }
+    }
+
+    if is_launch_intent(prompt) {
+        // Launching something already present needs no install step at all
+        remapped.retain(|(c, _)| {
+            let keep = !is_installed_notice(c);
+            if !keep && config.verbose {
+                let line = format!("-> dropped LLM command `{c}`: already installed, only the launch remains");
+                eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+            }
+            keep
+        });
+
+        // If this was a launch intent and we only have installs, add a launch step
+        if !remapped.iter().any(|(c, _)| c.starts_with("launch ")) {
+            if let Some(app) = launch_app {
+                remapped.push((format!("launch {}", app), None));
+            }
+        }
+    }
+
+    Ok(remapped)
+}
+
+fn openai_chat(
+    client: &HttpClient,
+    system_prompt: &str,
+    prompt: &str,
+    config: &ExecConfig,
+) -> Result<String, AssistError> {
+    let base = openai_base_url();
+    // Local gateways (llama.cpp, vLLM, LiteLLM) usually run without auth
+    let api_key = match std::env::var("OPENAI_API_KEY") {
+        Ok(key) => Some(key),
+        Err(_) if is_local_url(&base) => None,
+        Err(_) => return Err(AssistError::CommandFailed("OPENAI_API_KEY not set".into())),
+    };
+
+    let req_body = openai_request(system_prompt, prompt, config);
+
+    let mut request = client
+        .post(chat_completions_url(&base))
+        .header("Content-Type", "application/json");
+    if let Some(key) = api_key {
+        request = request.header("Authorization", format!("Bearer {key}"));
+    }
+    let resp: ChatResponse = send_with_retry(request.json(&req_body), "llm call", config)
+        .map_err(|e| http_error("llm call", e, config))?
+        .error_for_status()
+        .map_err(|e| http_error("llm call", e, config))?
+        .json()
+        .map_err(|e| http_error("llm decode", e, config))?;
+
+    if resp.choices.is_empty() {
+        return Err(AssistError::Network("LLM returned no choices".into()));
+    }
+
+    resp.choices
+        .first()
+        .and_then(|c| c.message.content.clone())
+        .ok_or_else(|| AssistError::Network("LLM returned no content".into()))
+}
+
+fn openai_request(system_prompt: &str, prompt: &str, config: &ExecConfig) -> ChatRequest {
+    ChatRequest {
+        model: llm_model(config),
+        max_completion_tokens: Some(config.max_tokens),
+        temperature: Some(config.temperature),
+        messages: vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: vec![ChatContent {
+                    kind: "text".to_string(),
+                    text: system_prompt.to_string(),
+                }],
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: vec![ChatContent {
+                    kind: "text".to_string(),
+                    text: prompt.to_string(),
+                }],
+            },
+        ],
+    }
+}
+
+/// Ollama and local OpenAI-compatible gateways work offline; only a remote API is blocked.
+fn llm_reachable(config: &ExecConfig) -> bool {
+    let base = match config.backend {
+        Backend::OpenAi => openai_base_url(),
+        Backend::Ollama => ollama_host(),
+    };
+    !config.offline || is_local_url(&base)
+}
+
+fn llm_chat(
+    client: &HttpClient,
+    system_prompt: &str,
+    prompt: &str,
+    config: &ExecConfig,
+) -> Result<String, AssistError> {
+    let _spinner = Spinner::start(
+        &format!("waiting for {}", config.backend.name()),
+        !config.quiet && io::stderr().is_terminal(),
+    );
+    match config.backend {
+        Backend::OpenAi => openai_chat(client, system_prompt, prompt, config),
+        Backend::Ollama => ollama_chat(client, system_prompt, prompt, config),
+    }
+}
+
+const EXPLAIN_SYSTEM_PROMPT: &str = "You are an Arch Linux expert. Explain what the given shell command does \
+in plain English for a newcomer, in at most four short sentences, then list any risks (data loss, \
+network use, needing root) on a final line starting with 'Risks:'. Do not suggest other commands.";
+
+const EXPLAIN_ONE_LINE_PROMPT: &str = "You are an Arch Linux expert. In one short sentence, say what the \
+given shell command does. No markdown, no alternatives.";
+
+/// `--explain-before-run`: the suggestion's reason plus a one-line description, printed just
+/// before the command runs. Built-in notes come first; the LLM only covers what they don't.
+fn annotate(sugg: &Suggestion, config: &ExecConfig) {
+    let explanation = explain_builtin(&sugg.cmd)
+        .and_then(|notes| notes.lines().next().map(str::to_string))
+        .or_else(|| {
+            if !llm_reachable(config) {
+                return None;
+            }
+            let client = http_client(config).ok()?;
+            let text = llm_chat(&client, EXPLAIN_ONE_LINE_PROMPT, &sugg.cmd, config).ok()?;
+            text.lines().map(str::trim).find(|l| !l.is_empty()).map(str::to_string)
+        });
+    let mut lines = vec![format!("# {}", sugg.reason)];
+    lines.extend(explanation.map(|text| format!("# {text}")));
+    for line in lines {
+        let painted = Paint::Dim.apply(&line, config.color, config.json);
+        // Keep stdout clean for --json consumers
+        if config.json {
+            eprintln!("{painted}");
+        } else {
+            println!("{painted}");
+        }
+    }
+}
+
+/// Describes a command without running it: the LLM when reachable, else the built-in table.
+pub fn handle_explain(command: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    if llm_reachable(config) {
+        let answer =
+            http_client(config).and_then(|client| llm_chat(&client, EXPLAIN_SYSTEM_PROMPT, command, config));
+        match answer {
+            Ok(text) if !text.trim().is_empty() => {
+                println!("{}", text.trim());
+                return Ok(());
+            }
+            Ok(_) => eprintln!("LLM returned no explanation; using the built-in notes"),
+            Err(e) => eprintln!("{e}; using the built-in notes"),
+        }
+    }
+    let notes = explain_builtin(command)
+        .ok_or_else(|| AssistError::CommandFailed(format!("no built-in notes for '{command}'")))?;
+    println!("{notes}");
+    Ok(())
+}
+
+/// Offline notes keyed on the program (after sudo/doas) and its first verb or flag.
+fn explain_builtin(command: &str) -> Option<String> {
+    let (program, args) = split_program(command);
+    let first = args.first().copied().unwrap_or("");
+    let what = match program {
+        "pacman" => explain_pacman_op(first, "pacman")?,
+        helper if is_aur_helper(helper) => {
+            if first.is_empty() {
+                "Refreshes the package databases and upgrades repo and AUR packages.".to_string()
+            } else {
+                explain_pacman_op(first, helper)?
+            }
+        }
+        "systemctl" => {
+            let unit = args.iter().copied().filter(|a| !a.starts_with('-')).nth(1).unwrap_or("the unit");
+            let now = if args.contains(&"--now") { " and applies it immediately" } else { "" };
+            match first {
+                "start" => format!("Starts {unit} now (until reboot)."),
+                "stop" => format!("Stops {unit} now."),
+                "restart" => format!("Stops and starts {unit} again."),
+                "reload" => format!("Asks {unit} to reload its configuration."),
+                "enable" => format!("Makes {unit} start at boot{now}."),
+                "disable" => format!("Stops {unit} from starting at boot{now}."),
+                "status" => format!("Shows whether {unit} is running plus its latest log lines."),
+                _ => return None,
+            }
+        }
+        "journalctl" => "Shows systemd journal logs (`-u` picks a unit, `-b` limits to this boot).".to_string(),
+        "nmcli" => "Queries or changes NetworkManager connections and devices.".to_string(),
+        "pactl" => "Queries or controls the PulseAudio/PipeWire sound server.".to_string(),
+        "bluetoothctl" => "Queries or controls Bluetooth adapters and devices.".to_string(),
+        "timedatectl" => "Shows or changes the clock, timezone and NTP sync.".to_string(),
+        "hostnamectl" => "Shows or changes the machine's hostname.".to_string(),
+        "localectl" => "Shows or changes the system locale and the console/X11 keymap.".to_string(),
+        "pacman-key" => "Manages the keyring pacman uses to verify package signatures.".to_string(),
+        "mkinitcpio" => "Rebuilds the initramfs images (`-P` covers every kernel preset).".to_string(),
+        "reflector" => "Downloads the mirror status list and rewrites the pacman mirrorlist with the fastest ones."
+            .to_string(),
+        "checkupdates" => "Lists pending updates using a temporary database copy; installs nothing.".to_string(),
+        "launch" => "Starts a desktop application in the background (arch-assist internal verb).".to_string(),
+        "remove-orphans" => {
+            "Finds packages no longer needed as dependencies and removes them (arch-assist internal verb)."
+                .to_string()
+        }
+        "unlock-pacman-db" => {
+            "Removes a stale pacman lock file if no pacman is running, after asking (arch-assist internal verb)."
+                .to_string()
+        }
+        "flatpak" => "Installs, updates or runs sandboxed Flatpak apps (usually from Flathub).".to_string(),
+        "lsblk" => "Lists disks and partitions; `-f` adds filesystems, labels and mount points.".to_string(),
+        "udisksctl" => "Mounts, unmounts or inspects drives as your user through udisks, without sudo.".to_string(),
+        "ssh-keygen" => {
+            "Creates an SSH key pair: the private key at `-f` and the public one next to it as `.pub`.".to_string()
+        }
+        "snapper" => "Creates and manages btrfs snapshots you can roll back to.".to_string(),
+        "ufw" => "Manages the uncomplicated firewall: enabling it and allowing or denying ports.".to_string(),
+        "firewall-cmd" => "Changes firewalld zones, ports and services; `--permanent` needs a `--reload`.".to_string(),
+        "pgrep" => "Lists the PIDs of processes matching a name; it changes nothing.".to_string(),
+        "kill" => "Sends a signal (TERM unless another is given) to the listed PIDs.".to_string(),
+        "list-installed" => {
+            "Lists installed packages whose line contains a word (arch-assist internal verb).".to_string()
+        }
+        "kill-process" => {
+            "Looks up a process by exact name and kills it after asking (arch-assist internal verb).".to_string()
+        }
+        "pkgfile" => "Looks up which repo package provides a file or command, using its own database.".to_string(),
+        "upower" => "Lists power devices (`-e`) or shows one device's charge and state (`-i`).".to_string(),
+        "battery-status" => {
+            "Finds the battery through upower and prints its charge and state (arch-assist internal verb).".to_string()
+        }
+        "df" => "Shows used and free space per mounted filesystem; it changes nothing.".to_string(),
+        "du" => "Adds up the disk usage of files and directories; it changes nothing.".to_string(),
+        "echo" => "Prints its arguments; it changes nothing.".to_string(),
+        _ => return None,
+    };
+    let root = if is_privilege_wrapper(command.split_whitespace().next().unwrap_or("")) {
+        "\nRuns as root."
+    } else {
+        ""
+    };
+    Some(format!("{what}{root}"))
+}
+
+fn explain_pacman_op(op: &str, tool: &str) -> Option<String> {
+    let mods = op.strip_prefix('-')?;
+    let (verb, mods) = mods.split_at(mods.len().min(1));
+    Some(match verb {
+        "S" if mods.contains('s') => "Searches the package databases; changes nothing.".to_string(),
+        "S" if mods.contains('i') => "Shows package details from the sync database.".to_string(),
+        "S" if mods.contains('c') => "Removes old packages from the package cache to free disk space.".to_string(),
+        "S" if mods.contains('u') => {
+            "Refreshes the package databases and upgrades every installed package (full system upgrade).".to_string()
+        }
+        "S" if mods.contains('y') => {
+            "Refreshes the package databases (and installs any named packages; a partial upgrade risk).".to_string()
+        }
+        "S" => format!("Downloads and installs the named packages with {tool}."),
+        "R" if mods.contains('s') || mods.contains('n') => {
+            "Removes the named packages along with unneeded dependencies and config files.".to_string()
+        }
+        "R" => "Removes the named packages.".to_string(),
+        "Q" => "Queries the local database of installed packages; changes nothing.".to_string(),
+        "U" => "Installs packages from local package files.".to_string(),
+        "F" if mods.contains('y') => "Downloads the file lists of every repo so `-F` can search them.".to_string(),
+        "F" => "Searches the repos' file lists for the package that owns a file; changes nothing.".to_string(),
+        _ => return None,
+    })
+}
+
+/// Format and safety rules the command parser and `validate` rely on. Always appended, even to
+/// a custom system prompt.
+const SYSTEM_PROMPT_RULES: &str = "Respond with ONLY shell commands, one per line, each followed by a tab \
+and '# ' with a short reason (e.g. `sudo pacman -S vlc\t# install the VLC player`). \
+Do not suggest generic shells (bash/sh) as commands. Never use dangerous operators (rm, dd, mkfs, pipes, redirects).";
+
+fn build_system_prompt(installed_list: &str, config: &ExecConfig) -> String {
+    let intro = match &config.system_prompt {
+        Some(custom) => custom.trim().to_string(),
+        None => format!(
+            "You are an Arch Linux expert. Use pacman for repo packages; use {helper} for AUR packages (e.g., *-bin). \
+Keep responses concise and focused on the requested task.",
+            helper = aur_helper(config).unwrap_or("paru")
+        ),
+    };
+    format!("{intro}\nInstalled packages (names only): {installed_list}.\n{SYSTEM_PROMPT_RULES}")
+}
+
+/// Animates a stderr line until dropped, so a slow LLM call does not look like a hang.
+struct Spinner {
+    stop: Arc<AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+    width: usize,
+}
+
+impl Spinner {
+    fn start(label: &str, enabled: bool) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let width = label.len() + 2;
+        let handle = enabled.then(|| {
+            let stop = Arc::clone(&stop);
+            let label = label.to_string();
+            std::thread::spawn(move || {
+                for frame in ['|', '/', '-', '\\'].iter().cycle() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    eprint!("\r{frame} {label}");
+                    let _ = io::stderr().flush();
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            })
+        });
+        Spinner { stop, handle, width }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+            eprint!("\r{}\r", " ".repeat(self.width));
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+/// Backend model: `--model`, then `OPENAI_MODEL`/`OLLAMA_MODEL`, then `model` from the config file.
+fn llm_model(config: &ExecConfig) -> String {
+    let (var, default) = match config.backend {
+        Backend::OpenAi => ("OPENAI_MODEL", "gpt-4o-mini"),
+        Backend::Ollama => ("OLLAMA_MODEL", "llama3.1"),
+    };
+    config
+        .model_override
+        .clone()
+        .or_else(|| std::env::var(var).ok())
+        .or_else(|| config.model.clone())
+        .unwrap_or_else(|| default.to_string())
+}
+
+#[derive(Serialize, Deserialize)]
+struct LlmCacheEntry {
+    content: String,
+    checked_at: u64,
+}
+
+/// FNV-1a over the request parts; stable across builds, unlike `DefaultHasher`.
+fn llm_cache_key(model: &str, system_prompt: &str, prompt: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in [model, system_prompt, prompt] {
+        for byte in part.bytes().chain(std::iter::once(0)) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+fn llm_cache_path(key: &str) -> Option<PathBuf> {
+    Some(cache_dir()?.join("llm").join(format!("{key}.json")))
+}
+
+fn load_llm_cache(key: &str, ttl: Duration) -> Option<String> {
+    if ttl.is_zero() {
+        return None;
+    }
+    let data = fs::read_to_string(llm_cache_path(key)?).ok()?;
+    let entry: LlmCacheEntry = serde_json::from_str(&data).ok()?;
+    (unix_now().saturating_sub(entry.checked_at) < ttl.as_secs()).then_some(entry.content)
+}
+
+fn store_llm_cache(key: &str, content: &str, ttl: Duration) {
+    if ttl.is_zero() {
+        return;
+    }
+    let Some(path) = llm_cache_path(key) else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    let entry = LlmCacheEntry {
+        content: content.to_string(),
+        checked_at: unix_now(),
+    };
+    if let Ok(body) = serde_json::to_string(&entry) {
+        let _ = fs::write(&path, body);
+    }
+}
+
+/// `OPENAI_TEMPERATURE`, else the config value; rejected outside 0–2 before any request is sent.
+pub fn llm_temperature(env: Option<&str>, file: Option<f32>) -> Result<f32, AssistError> {
+    let value = match env {
+        Some(raw) => raw.trim().parse::<f32>().map_err(|_| {
+            AssistError::CommandFailed(format!("OPENAI_TEMPERATURE must be a number, got '{raw}'"))
+        })?,
+        None => file.unwrap_or(ExecConfig::DEFAULT_TEMPERATURE),
+    };
+    if !(0.0..=2.0).contains(&value) {
+        return Err(AssistError::CommandFailed(format!(
+            "temperature must be between 0 and 2, got {value}"
+        )));
+    }
+    Ok(value)
+}
+
+/// `OPENAI_MAX_TOKENS`, else the config value; rejected outside 1–4096.
+pub fn llm_max_tokens(env: Option<&str>, file: Option<u32>) -> Result<u32, AssistError> {
+    let value = match env {
+        Some(raw) => raw.trim().parse::<u32>().map_err(|_| {
+            AssistError::CommandFailed(format!("OPENAI_MAX_TOKENS must be a whole number, got '{raw}'"))
+        })?,
+        None => file.unwrap_or(ExecConfig::DEFAULT_MAX_TOKENS),
+    };
+    if !(1..=4096).contains(&value) {
+        return Err(AssistError::CommandFailed(format!(
+            "max tokens must be between 1 and 4096, got {value}"
+        )));
+    }
+    Ok(value)
+}
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// `OPENAI_BASE_URL` lets proxies, Azure and OpenAI-compatible gateways stand in for the API.
+fn openai_base_url() -> String {
+    std::env::var("OPENAI_BASE_URL")
+        .ok()
+        .filter(|url| !url.trim().is_empty())
+        .unwrap_or_else(|| OPENAI_BASE_URL.to_string())
+}
+
+/// `OLLAMA_HOST`, else the local default. A remote host is off limits under `--offline`.
+fn ollama_host() -> String {
+    std::env::var("OLLAMA_HOST")
+        .ok()
+        .filter(|host| !host.trim().is_empty())
+        .unwrap_or_else(|| OLLAMA_DEFAULT_HOST.to_string())
+}
+
+const OLLAMA_DEFAULT_HOST: &str = "http://localhost:11434";
+
+fn chat_completions_url(base: &str) -> String {
+    format!("{}/chat/completions", base.trim().trim_end_matches('/'))
+}
+
+fn is_local_url(url: &str) -> bool {
+    let host = url
+        .split("://")
+        .nth(1)
+        .unwrap_or(url)
+        .split(['/', '?'])
+        .next()
+        .unwrap_or("");
+    // Strip the port; bracketed IPv6 literals keep their colons
+    let host = match host.find(']') {
+        Some(end) if host.starts_with('[') => &host[..=end],
+        _ => host.split(':').next().unwrap_or(host),
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+fn ollama_chat(
+    client: &HttpClient,
+    system_prompt: &str,
+    prompt: &str,
+    config: &ExecConfig,
+) -> Result<String, AssistError> {
+    let host = ollama_host();
+    let model = llm_model(config);
+
+    let req_body = OllamaRequest {
+        model,
+        stream: false,
+        messages: vec![
+            OllamaMessage {
+                role: "system".to_string(),
+                content: system_prompt.to_string(),
+            },
+            OllamaMessage {
+                role: "user".to_string(),
+                content: prompt.to_string(),
+            },
+        ],
+    };
+
+    let request = client.post(format!("{}/api/chat", host.trim_end_matches('/'))).json(&req_body);
+    let resp: OllamaResponse = send_with_retry(request, "ollama call", config)
+        .map_err(|e| http_error("ollama call", e, config))?
+        .error_for_status()
+        .map_err(|e| http_error("ollama call", e, config))?
+        .json()
+        .map_err(|e| http_error("ollama decode", e, config))?;
+
+    resp.message
+        .content
+        .ok_or_else(|| AssistError::Network("LLM returned no content".into()))
+}
+
+fn adjust_commands_for_intent(cmds: Vec<String>, prompt: &str, config: &ExecConfig) -> Vec<String> {
+    let prompt_lower = prompt.to_lowercase();
+    let desired_pkg = if prompt_lower.contains("word") || prompt_lower.contains("office") {
+        Some("libreoffice-fresh")
+    } else {
+        None
+    };
+    let is_launch_intent = is_launch_intent(&prompt_lower);
+    let yay_configured = matches!(aur_helper(config), Ok("yay"));
+
+    let mut out = Vec::new();
+    for cmd in &cmds {
+        // Drop suggestions that install helper tools we don't want
+        if !yay_configured && (cmd.contains(" yay") || cmd.starts_with("yay ") || cmd == "yay") {
+            continue;
+        }
+
+        if let Some(pkg) = desired_pkg {
+            if let Some(rewritten) = rewrite_install_pkg(cmd, pkg) {
+                out.push(rewritten);
+                continue;
+            }
+        }
+
+        if is_launch_intent && needs_launch_wrapper(cmd) {
+            out.push(format!("launch {}", cmd));
+            continue;
+        }
+
+        out.push(cmd.clone());
+    }
+
+    if out.is_empty() {
+        return cmds;
+    }
+
+    out
+}
+
+fn rewrite_install_pkg(cmd: &str, new_pkg: &str) -> Option<String> {
+    let parts: Vec<&str> = cmd.split_whitespace().collect();
+    if parts.len() < 2 {
+        return None;
+    }
+
+    let (tool, rest) = (parts[0], &parts[1..]);
+    let wrapped_pacman = is_privilege_wrapper(tool) && rest.first() == Some(&"pacman");
+    if tool != "pacman" && !is_aur_helper(tool) && !wrapped_pacman {
+        return None;
+    }
+
+    let mut installer = tool.to_string();
+    let mut args = rest;
+    if wrapped_pacman {
+        installer = format!("{tool} pacman");
+        args = &rest[1..];
+    }
+
+    if args.is_empty() || !args[0].starts_with("-S") {
+        return None;
+    }
+
+    let mut new_args = args.to_vec();
+    if let Some(last) = new_args.last_mut() {
+        *last = new_pkg;
+    }
+    let rebuilt = format!("{} {}", installer, new_args.join(" "));
+    Some(rebuilt)
+}
+
+fn needs_launch_wrapper(cmd: &str) -> bool {
+    let mut parts = cmd.split_whitespace();
+    let first = parts.next().unwrap_or("");
+    if ALLOWED_PROGRAMS.contains(&first) {
+        return false;
+    }
+    // If it's a single token (likely app name), wrap it
+    !cmd.contains(' ')
+}
+
+fn extract_app_name_from_install(cmds: &[String]) -> Option<String> {
+    for cmd in cmds {
+        let parts: Vec<&str> = cmd.split_whitespace().collect();
+        if parts.len() >= 2 && parts[0] == "launch" {
+            return Some(parts[1].to_string());
+        }
+        if parts.len() >= 3 && is_privilege_wrapper(parts[0]) && parts[1] == "pacman" && parts[2].starts_with("-S") {
+            if let Some(pkg) = parts.last() {
+                return Some((*pkg).to_string());
+            }
+        }
+        if parts.len() >= 2 && parts[0] == "pacman" && parts[1].starts_with("-S") {
+            if let Some(pkg) = parts.last() {
+                return Some((*pkg).to_string());
+            }
+        }
+        if parts.len() >= 2 && is_aur_helper(parts[0]) && parts[1].starts_with("-S") {
+            if let Some(pkg) = parts.last() {
+                return Some((*pkg).to_string());
+            }
+        }
+    }
+    None
+}
+
+fn is_launch_intent(prompt: &str) -> bool {
+    let prompt_lower = prompt.to_lowercase();
+    ["open", "launch", "start"]
+        .iter()
+        .any(|k| prompt_lower.starts_with(k))
+}
+
+fn rewrite_install_with_resolution(
+    cmd: String,
+    config: &ExecConfig,
+    resolver: &dyn PackageResolver,
+) -> Result<Vec<String>, AssistError> {
+    let parts: Vec<&str> = cmd.split_whitespace().collect();
+    let args = if parts.len() >= 3 && is_privilege_wrapper(parts[0]) && parts[1] == "pacman" {
+        &parts[2..]
+    } else if parts.len() >= 2 && parts[0] == "pacman" {
+        &parts[1..]
+    } else {
+        return Ok(vec![cmd]);
+    };
+    if !is_install_op(args[0]) {
+        return Ok(vec![cmd]);
+    }
+
+    let (flags, pkgs): (Vec<&str>, Vec<&str>) = args.iter().partition(|a| a.starts_with('-'));
+    if pkgs.is_empty() {
+        return Ok(vec![cmd]);
+    }
+    Ok(resolve_installer(&flags.join(" "), &pkgs, config, resolver)?.unwrap_or_else(|| vec![cmd]))
+}
+
+/// `-S`, `-Sy`, `-Syu` install; `-Ss`/`-Si`/`-Sc` and friends only query or clean.
+fn is_install_op(op: &str) -> bool {
+    op.strip_prefix("-S")
+        .map(|mods| !mods.chars().any(|c| "silgcp".contains(c)))
+        .unwrap_or(false)
+}
+
+fn resolve_installer(
+    flags: &str,
+    pkgs: &[&str],
+    config: &ExecConfig,
+    resolver: &dyn PackageResolver,
+) -> Result<Option<Vec<String>>, AssistError> {
+    let missing: Vec<&str> = pkgs
+        .iter()
+        .copied()
+        .filter(|pkg| config.reinstall || !is_installed_locally(pkg))
+        .collect();
+    if missing.is_empty() {
+        return Ok(Some(vec![installed_notice(&pkgs.join(" "))]));
+    }
+    if config.offline {
+        return Ok(None);
+    }
+    resolver.prefetch(&missing);
+    plan_install(&missing, flags, config, |pkg| resolve_with_hints(pkg, config, resolver)).map(Some)
+}
+
+/// Groups packages into at most one pacman, one AUR-helper and one flatpak command,
+/// ordered by whichever group's first package appeared first.
+fn plan_install(
+    pkgs: &[&str],
+    flags: &str,
+    config: &ExecConfig,
+    origin_of: impl Fn(&str) -> Result<PackageOrigin, AssistError> + Sync,
+) -> Result<Vec<String>, AssistError> {
+    // --prefer-paru skips resolution entirely
+    let origins = if config.prefer_paru {
+        vec![None; pkgs.len()]
+    } else {
+        resolve_all(pkgs, origin_of)?.into_iter().map(Some).collect()
+    };
+    let mut groups: Vec<(&str, Vec<String>)> = Vec::new();
+    let mut disabled = Vec::new();
+    for (pkg, origin) in pkgs.iter().zip(origins) {
+        let guessed_aur =
+            matches!(origin, Some(PackageOrigin::Unknown | PackageOrigin::Offline | PackageOrigin::Undetermined))
+                .then(|| is_probably_aur(pkg, config));
+        let (installer, name) = match origin {
+            None | Some(PackageOrigin::Aur) => ("aur", pkg.to_string()),
+            Some(PackageOrigin::Repo) => ("pacman", pkg.to_string()),
+            Some(PackageOrigin::Unknown | PackageOrigin::Offline | PackageOrigin::Undetermined)
+                if guessed_aur == Some(true) =>
+            {
+                ("aur", pkg.to_string())
+            }
+            Some(PackageOrigin::Unknown | PackageOrigin::Offline | PackageOrigin::Undetermined) => {
+                ("pacman", pkg.to_string())
+            }
+            Some(PackageOrigin::Flatpak) => match flatpak_app_id(pkg) {
+                Some(id) => ("flatpak", id),
+                None => ("pacman", pkg.to_string()),
+            },
+            // lookup_origin already explained how to enable the repo
+            Some(PackageOrigin::RepoDisabled) => {
+                log_routing(pkg, origin, guessed_aur, "none", config);
+                disabled.push(*pkg);
+                continue;
+            }
+        };
+        let shown = if installer == "aur" { aur_helper(config).unwrap_or("an AUR helper") } else { installer };
+        log_routing(pkg, origin, guessed_aur, shown, config);
+        match groups.iter_mut().find(|(group, _)| *group == installer) {
+            Some((_, names)) => names.push(name),
+            None => groups.push((installer, vec![name])),
+        }
+    }
+
+    // An empty plan would look like success; say why nothing is left to install
+    if groups.is_empty() && !disabled.is_empty() {
+        return Err(AssistError::CommandFailed(format!(
+            "nothing to install: {} only in repos not enabled in {PACMAN_CONF} (see above)",
+            disabled.join(", ")
+        )));
+    }
+
+    groups
+        .into_iter()
+        .map(|(installer, names)| {
+            let names = names.join(" ");
+            Ok(match installer {
+                "flatpak" => format!("flatpak install -y flathub {names}"),
+                "aur" => apply_pkg_flags(format!("{} {flags} {names}", aur_helper(config)?), config),
+                _ => apply_pkg_flags(format!("{} {flags} {names}", pacman_cmd(config)), config),
+            })
+        })
+        .collect()
+}
+
+/// Most package lookups in flight at once when a batch is resolved.
+const RESOLVE_CONCURRENCY: usize = 8;
+
+/// `origin_of` for every package on up to `RESOLVE_CONCURRENCY` threads. Results come back in
+/// the order of `pkgs`; the first package (in that order) that failed decides the error.
+fn resolve_all(
+    pkgs: &[&str],
+    origin_of: impl Fn(&str) -> Result<PackageOrigin, AssistError> + Sync,
+) -> Result<Vec<PackageOrigin>, AssistError> {
+    if pkgs.len() < 2 {
+        return pkgs.iter().map(|pkg| origin_of(pkg)).collect();
+    }
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<PackageOrigin, AssistError>>>> =
+        Mutex::new(pkgs.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..pkgs.len().min(RESOLVE_CONCURRENCY) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(pkg) = pkgs.get(i) else {
+                    break;
+                };
+                let origin = origin_of(pkg);
+                if let Ok(mut slots) = results.lock() {
+                    slots[i] = Some(origin);
+                }
+            });
+        }
+    });
+    let slots = results.into_inner().unwrap_or_default();
+    slots
+        .into_iter()
+        .map(|slot| slot.unwrap_or_else(|| Err(AssistError::CommandFailed("package lookup thread panicked".into()))))
+        .collect()
+}
+
+/// The Flathub application id for `pkg` from `flatpak search`, looked up once per process.
+/// `None` when flatpak is missing or no id ends in (or contains) the name.
+fn flatpak_app_id(pkg: &str) -> Option<String> {
+    static IDS: OnceLock<Mutex<HashMap<String, Option<String>>>> = OnceLock::new();
+    let ids = IDS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(known) = ids.lock().ok()?.get(pkg) {
+        return known.clone();
+    }
+    let found = Command::new("flatpak")
+        .args(["search", "--columns=application", pkg])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| pick_flatpak_id(pkg, &String::from_utf8_lossy(&out.stdout)));
+    ids.lock().ok()?.insert(pkg.to_string(), found.clone());
+    found
+}
+
+/// Picks the id whose last segment is `name` (org.videolan.VLC for "vlc"), else the first
+/// id containing it. Header lines and anything that isn't a reverse-DNS id are ignored.
+fn pick_flatpak_id(name: &str, listing: &str) -> Option<String> {
+    let wanted = name.to_lowercase();
+    let ids: Vec<&str> = listing
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.contains('.') && !line.contains(char::is_whitespace))
+        .collect();
+    ids.iter()
+        .find(|id| id.rsplit('.').next().is_some_and(|last| last.to_lowercase() == wanted))
+        .or_else(|| ids.iter().find(|id| id.to_lowercase().contains(&wanted)))
+        .map(|id| id.to_string())
+}
+
+/// An installed flatpak app matching `app` the same way `pick_flatpak_id` does.
+fn installed_flatpak(app: &str) -> Option<String> {
+    let out = Command::new("flatpak")
+        .args(["list", "--app", "--columns=application"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|out| out.status.success())?;
+    pick_flatpak_id(app, &String::from_utf8_lossy(&out.stdout))
+}
+
+/// "firefox, vlc and neovim" -> ["firefox", "vlc", "neovim"]
+fn split_package_list(rest: &str) -> Vec<&str> {
+    rest.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty() && *w != "and")
+        .collect()
+}
+
+fn build_install_command(
+    pkg: &str,
+    flags: &str,
+    config: &ExecConfig,
+    resolver: &dyn PackageResolver,
+) -> Result<Option<String>, AssistError> {
+    if !config.reinstall && is_installed_locally(pkg) {
+        return Ok(Some(installed_notice(pkg)));
+    }
+
+    let resolution = resolve_with_hints(pkg, config, resolver)?;
+    let guessed_aur = matches!(resolution, PackageOrigin::Unknown | PackageOrigin::Undetermined)
+        .then(|| is_probably_aur(pkg, config));
+    let cmd = match resolution {
+        PackageOrigin::Repo => Some(format!("{} {flags} {pkg}", pacman_cmd(config))),
+        PackageOrigin::Aur => Some(format!("{} {flags} {pkg}", aur_helper(config)?)),
+        PackageOrigin::Unknown | PackageOrigin::Undetermined => {
+            if guessed_aur == Some(true) {
+                Some(format!("{} {flags} {pkg}", aur_helper(config)?))
+            } else {
+                Some(format!("{} {flags} {pkg}", pacman_cmd(config)))
+            }
+        }
+        PackageOrigin::Flatpak => flatpak_app_id(pkg).map(|id| format!("flatpak install -y flathub {id}")),
+        PackageOrigin::Offline | PackageOrigin::RepoDisabled => None,
+    };
+    let installer = cmd.as_deref().map_or("none", |c| program_token(c));
+    log_routing(pkg, Some(resolution), guessed_aur, installer, config);
+    Ok(cmd)
+}
+
+/// `--verbose`: why a package went to pacman, the AUR helper or flatpak, so a wrong route
+/// (an AUR package sent to pacman) can be traced. `origin` is `None` under `--prefer-paru`,
+/// `guessed_aur` is `None` when the heuristic was not needed.
+fn log_routing(
+    pkg: &str,
+    origin: Option<PackageOrigin>,
+    guessed_aur: Option<bool>,
+    installer: &str,
+    config: &ExecConfig,
+) {
+    if !config.verbose {
+        return;
+    }
+    let origin = origin.map_or("not resolved (--prefer-paru)".to_string(), |o| format!("{o:?}"));
+    let heuristic = match guessed_aur {
+        Some(true) => "fired",
+        Some(false) => "did not fire",
+        None => "not consulted",
+    };
+    let line = format!("-> route {pkg}: origin {origin}, is_probably_aur {heuristic}, installer {installer}");
+    eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+}
+
+/// Like `resolver.resolve`, but an Unknown answer first prints close package names so a
+/// typo is visible before we fall back to guessing an installer.
+fn resolve_with_hints(
+    pkg: &str,
+    config: &ExecConfig,
+    resolver: &dyn PackageResolver,
+) -> Result<PackageOrigin, AssistError> {
+    let origin = resolver.resolve(pkg)?;
+    if matches!(origin, PackageOrigin::Unknown) && config.suggestions {
+        // A typo rarely matches as a substring, so search on a leading stem instead
+        let stem: String = pkg
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .take((pkg.len() / 2).max(3))
+            .collect();
+        let matches = near_matches(pkg, &search_sync_db(&format!("^{stem}")), 5);
+        if let Some((best, rest)) = matches.split_first() {
+            eprintln!("{pkg}: not found in the repos or the AUR; did you mean {best}?");
+            if !rest.is_empty() {
+                eprintln!("  other close matches: {}", rest.join(", "));
+            }
+        }
+    }
+    Ok(origin)
+}
+
+/// Package names from `pacman -Ssq`, which matches names and descriptions.
+fn search_sync_db(query: &str) -> Vec<String> {
+    Command::new("pacman")
+        .args(["-Ssq", query])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .map(|out| String::from_utf8_lossy(&out.stdout).lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Up to `limit` candidates ranked by edit distance to `pkg`, skipping an exact match.
+fn near_matches(pkg: &str, candidates: &[String], limit: usize) -> Vec<String> {
+    let mut ranked: Vec<(usize, &String)> = candidates
+        .iter()
+        .filter(|c| c.as_str() != pkg)
+        .map(|c| (edit_distance(pkg, c), c))
+        .collect();
+    ranked.sort();
+    ranked.dedup_by(|a, b| a.1 == b.1);
+    ranked.into_iter().take(limit).map(|(_, c)| c.clone()).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Well-known AUR package names, one per line with `#` comments (see the file's header).
+const COMMON_AUR: &str = include_str!("common_aur.txt");
+
+/// Offline guess for packages the repos and the AUR could not be asked about: VCS/binary
+/// suffixes, `common_aur.txt`, then `extra_aur_packages` from config.toml.
+fn is_probably_aur(pkg: &str, config: &ExecConfig) -> bool {
+    let aur_suffixes = ["-bin", "-git", "-svn", "-hg"];
+    if aur_suffixes.iter().any(|s| pkg.ends_with(s)) {
+        return true;
+    }
+    let mut common = COMMON_AUR.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#'));
+    common.any(|name| name == pkg) || config.extra_aur_packages.iter().any(|name| name == pkg)
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageOrigin {
+    Repo,
+    Aur,
+    Unknown,
+    Offline,
+    /// In an official repo that is not enabled in pacman.conf (e.g. multilib)
+    RepoDisabled,
+    /// On Flathub, chosen because of `--prefer-flatpak`
+    Flatpak,
+    /// Not in the repos, and the AUR could not be asked (rate limited or failing); unlike
+    /// `Unknown` this says nothing about whether the package exists
+    Undetermined,
+}
+
+/// Decides where a package comes from. Kept behind a trait so installer selection can be
+/// tested without the network; `Sync` because a batch of packages is resolved in parallel.
+pub trait PackageResolver: Sync {
+    fn resolve(&self, pkg: &str) -> Result<PackageOrigin, AssistError>;
+
+    /// Called with a whole batch before `resolve` runs on each member, so a resolver can
+    /// answer several packages with one request. The default does nothing.
+    fn prefetch(&self, _pkgs: &[&str]) {}
+}
+
+/// The real resolver: on-disk cache, then archlinux.org and the AUR RPC.
+pub struct HttpResolver<'a> {
+    pub config: &'a ExecConfig,
+}
+
+impl PackageResolver for HttpResolver<'_> {
+    fn resolve(&self, pkg: &str) -> Result<PackageOrigin, AssistError> {
+        resolve_package(pkg, self.config)
+    }
+
+    /// One AUR multi-info request for every uncached package; `lookup_origin` then reads the
+    /// answers instead of asking the AUR per package. A failed batch leaves them to that path.
+    fn prefetch(&self, pkgs: &[&str]) {
+        let config = self.config;
+        if config.offline || config.assume_origin.is_some() {
+            return;
+        }
+        let pending: Vec<&str> = pkgs.iter().copied().filter(|pkg| cached_origin(pkg, config).is_none()).collect();
+        if pending.len() < 2 {
+            return;
+        }
+        // Undetermined answers are left out so the per-package lookup can try again
+        if let (Ok(found), Ok(mut known)) = (check_aur_batch(&pending, config, AUR_RPC_URL), aur_answers().lock()) {
+            known.extend(found.into_iter().filter_map(|(pkg, answer)| Some((pkg, answer?))));
+        }
+    }
+}
+
+/// AUR membership learned from batch requests during this run.
+fn aur_answers() -> &'static Mutex<HashMap<String, bool>> {
+    static ANSWERS: OnceLock<Mutex<HashMap<String, bool>>> = OnceLock::new();
+    ANSWERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn resolve_package(pkg: &str, config: &ExecConfig) -> Result<PackageOrigin, AssistError> {
+    if let Some(origin) = config.assume_origin {
+        return Ok(origin);
+    }
+    if config.prefer_flatpak && !config.offline && flatpak_app_id(pkg).is_some() {
+        return Ok(PackageOrigin::Flatpak);
+    }
+
+    // The local sync DB answers repo membership without the network
+    if in_sync_db(pkg) == Some(true) {
+        return Ok(PackageOrigin::Repo);
+    }
+
+    if config.offline {
+        return Ok(PackageOrigin::Offline);
+    }
+
+    if let Some(origin) = cached_origin(pkg, config) {
+        return Ok(origin);
+    }
+
+    let origin = lookup_origin(pkg, config, ARCH_SEARCH_URL, AUR_RPC_URL)?;
+    remember_origin(pkg, origin, config);
+    Ok(origin)
+}
+
+/// `pacman -Si` against the local sync DB. `None` when pacman is unavailable; `Some(false)`
+/// may just mean the DB is stale, so callers still ask the web before giving up.
+fn in_sync_db(pkg: &str) -> Option<bool> {
+    Command::new("pacman")
+        .args(["-Si", pkg])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .ok()
+        .map(|s| s.success())
+}
+
+const ARCH_SEARCH_URL: &str = "https://archlinux.org/packages/search/json/";
+const AUR_RPC_URL: &str = "https://aur.archlinux.org/rpc/";
+
+/// Queries the repo search and the AUR at the same time; the repo wins when both know the package.
+fn lookup_origin(
+    pkg: &str,
+    config: &ExecConfig,
+    arch_url: &str,
+    aur_url: &str,
+) -> Result<PackageOrigin, AssistError> {
+    // A batch request may already have answered the AUR side
+    let batched = aur_answers().lock().ok().and_then(|known| known.get(pkg).copied());
+    let (in_repo, in_aur) = std::thread::scope(|scope| {
+        let repo = scope.spawn(|| check_arch_repo(pkg, config, arch_url));
+        let aur = scope.spawn(move || batched.map_or_else(|| check_aur(pkg, config, aur_url), |found| Ok(Some(found))));
+        (join_lookup(repo), join_lookup(aur))
+    });
+
+    let in_aur = in_aur?;
+    match in_repo? {
+        Some(repo) if repo_enabled(&repo) => Ok(PackageOrigin::Repo),
+        // An AUR package of the same name is a different build; don't silently swap it in
+        Some(repo) => {
+            eprintln!(
+                "{pkg} is in the [{repo}] repo, which is not enabled: uncomment the [{repo}] section \
+in {PACMAN_CONF} (and its Include line), then run `sudo pacman -Syu`"
+            );
+            Ok(PackageOrigin::RepoDisabled)
+        }
+        None if in_aur == Some(true) => Ok(PackageOrigin::Aur),
+        None if in_aur.is_none() => Ok(PackageOrigin::Undetermined),
+        None => Ok(PackageOrigin::Unknown),
+    }
+}
+
+const PACMAN_CONF: &str = "/etc/pacman.conf";
+
+/// Whether pacman.conf enables `repo`. An unreadable pacman.conf or an unnamed repo counts as
+/// enabled, so resolution never gets stricter than it was without the check.
+fn repo_enabled(repo: &str) -> bool {
+    static ENABLED: OnceLock<Option<HashSet<String>>> = OnceLock::new();
+    let enabled = ENABLED.get_or_init(|| fs::read_to_string(PACMAN_CONF).ok().map(|text| enabled_repos(&text)));
+    match enabled {
+        Some(repos) if !repo.is_empty() => repos.contains(repo),
+        _ => true,
+    }
+}
+
+/// Section names of pacman.conf other than `[options]`; commented-out sections don't count.
+fn enabled_repos(conf: &str) -> HashSet<String> {
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix('[')?.strip_suffix(']'))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| name != "options")
+        .collect()
+}
+
+fn join_lookup<T>(
+    handle: std::thread::ScopedJoinHandle<'_, Result<T, AssistError>>,
+) -> Result<T, AssistError> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(AssistError::CommandFailed("package lookup thread panicked".into())))
+}
+
+#[derive(Serialize, Deserialize)]
+struct PackageCacheEntry {
+    origin: PackageOrigin,
+    checked_at: u64,
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("arch-assist"))
+}
+
+fn package_cache_path() -> Option<PathBuf> {
+    Some(cache_dir()?.join("packages.json"))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn load_package_cache(ttl: Duration) -> HashMap<String, PackageCacheEntry> {
+    if ttl.is_zero() {
+        return HashMap::new();
+    }
+    let Some(data) = package_cache_path().and_then(|p| fs::read_to_string(p).ok()) else {
+        return HashMap::new();
+    };
+    let now = unix_now();
+    serde_json::from_str::<HashMap<String, PackageCacheEntry>>(&data)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, entry)| now.saturating_sub(entry.checked_at) < ttl.as_secs())
+        .collect()
+}
+
+/// Per-process lookup table, seeded from the on-disk cache on first use.
+fn resolution_cache(config: &ExecConfig) -> &'static Mutex<HashMap<String, PackageOrigin>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, PackageOrigin>>> = OnceLock::new();
+    CACHE.get_or_init(|| {
+        let seeded = load_package_cache(config.package_cache_ttl)
+            .into_iter()
+            .map(|(pkg, entry)| (pkg, entry.origin))
+            .collect();
+        Mutex::new(seeded)
+    })
+}
+
+fn cached_origin(pkg: &str, config: &ExecConfig) -> Option<PackageOrigin> {
+    resolution_cache(config).lock().ok()?.get(pkg).copied()
+}
+
+fn remember_origin(pkg: &str, origin: PackageOrigin, config: &ExecConfig) {
+    if let Ok(mut cache) = resolution_cache(config).lock() {
+        cache.insert(pkg.to_string(), origin);
+    }
+
+    // Unknown can just mean the lookup failed; only persist definite answers
+    if !matches!(origin, PackageOrigin::Repo | PackageOrigin::Aur) || config.package_cache_ttl.is_zero() {
+        return;
+    }
+    let Some(path) = package_cache_path() else {
+        return;
+    };
+    let mut disk = load_package_cache(config.package_cache_ttl);
+    disk.insert(
+        pkg.to_string(),
+        PackageCacheEntry {
+            origin,
+            checked_at: unix_now(),
+        },
+    );
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Ok(body) = serde_json::to_string_pretty(&disk) {
+        let _ = fs::write(&path, body);
+    }
+}
+
+fn http_client(config: &ExecConfig) -> Result<HttpClient, AssistError> {
+    HttpClient::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .build()
+        .map_err(|e| AssistError::CommandFailed(format!("http client ({e})")))
+}
+
+/// Sends `request`, retrying up to `--retries` times with exponential backoff on connection
+/// errors, 429 and 5xx. Other statuses (401, 404, ...) come back immediately.
+fn send_with_retry(
+    request: reqwest::blocking::RequestBuilder,
+    endpoint: &str,
+    config: &ExecConfig,
+) -> Result<reqwest::blocking::Response, reqwest::Error> {
+    let mut attempt = 0;
+    loop {
+        let Some(this_try) = (attempt < config.retries).then(|| request.try_clone()).flatten() else {
+            return request.send();
+        };
+        let reason = match this_try.send() {
+            Ok(resp) if is_retryable_status(resp.status()) => resp.status().to_string(),
+            Err(e) if e.is_connect() => e.to_string(),
+            other => return other,
+        };
+        attempt += 1;
+        let delay = Duration::from_millis(250 << (attempt - 1).min(6));
+        if config.verbose {
+            eprintln!(
+                "-> {endpoint}: {reason}; retry {attempt}/{} in {}ms",
+                config.retries,
+                delay.as_millis()
+            );
+        }
+        std::thread::sleep(delay);
+    }
+}
+
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn http_error(endpoint: &str, e: reqwest::Error, config: &ExecConfig) -> AssistError {
+    if e.is_timeout() {
+        AssistError::Network(format!(
+            "{endpoint} timed out after {}s (raise --timeout)",
+            config.timeout
+        ))
+    } else {
+        AssistError::Network(format!("{endpoint} ({e})"))
+    }
+}
+
+/// The repo holding exactly `pkg` (an enabled one when it is in several), or `None` when no
+/// result has that name or the request fails. A timeout is an `Err` instead, so the user learns why resolution
+/// stalled rather than getting a silent guess.
+fn check_arch_repo(pkg: &str, config: &ExecConfig, base: &str) -> Result<Option<String>, AssistError> {
+    let client = http_client(config)?;
+    let url = format!("{base}?q={}", urlencoding::encode(pkg));
+    match send_with_retry(client.get(url), "archlinux.org package search", config)
+        .and_then(|resp| resp.json::<ArchSearch>())
+    {
+        Ok(json) => {
+            let exact: Vec<String> = json
+                .results
+                .iter()
+                .filter(|r| r.pkgname == pkg)
+                .map(|r| r.repo.clone().unwrap_or_default().to_lowercase())
+                .collect();
+            Ok(pick_repo(&exact, repo_enabled))
+        }
+        Err(e) if e.is_timeout() => Err(http_error("archlinux.org package search", e, config)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The same package is often listed in several repos (`core-testing` and `core`); the first one
+/// pacman.conf enables wins, else the first listed so the caller can explain how to enable it.
+fn pick_repo(repos: &[String], enabled: impl Fn(&str) -> bool) -> Option<String> {
+    repos.iter().find(|repo| enabled(repo)).or(repos.first()).cloned()
+}
+
+/// `Some(found)` from a successful answer, `None` when the AUR could not say.
+fn check_aur(pkg: &str, config: &ExecConfig, base: &str) -> Result<Option<bool>, AssistError> {
+    Ok(check_aur_batch(&[pkg], config, base)?.get(pkg).copied().flatten())
+}
+
+/// AUR membership of every package in `pkgs` from a single multi-info request
+/// (`arg[]=a&arg[]=b`). Names the AUR does not return map to `Some(false)`; a non-2xx reply
+/// (429 once the retries are spent) or a failed request leaves every name at `None`, since
+/// "not in the AUR" would route an AUR package to pacman.
+fn check_aur_batch(
+    pkgs: &[&str],
+    config: &ExecConfig,
+    base: &str,
+) -> Result<HashMap<String, Option<bool>>, AssistError> {
+    let client = http_client(config)?;
+    let args: Vec<String> = pkgs.iter().map(|pkg| format!("arg[]={}", urlencoding::encode(pkg))).collect();
+    let url = format!("{base}?v=5&type=info&{}", args.join("&"));
+    let reply = send_with_retry(client.get(url), "AUR RPC", config)
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.json::<AurInfo>());
+    let found: HashSet<String> = match reply {
+        Ok(json) => json.results.into_iter().map(|r| r.name).collect(),
+        Err(e) if e.is_timeout() => return Err(http_error("AUR RPC", e, config)),
+        Err(e) => {
+            let why = e.status().map_or_else(|| e.to_string(), |status| status.to_string());
+            let line = format!("warning: AUR RPC gave no answer ({why}); guessing AUR membership from known names");
+            eprintln!("{}", Paint::Yellow.apply(&line, config.color, true));
+            return Ok(pkgs.iter().map(|pkg| (pkg.to_string(), None)).collect());
+        }
+    };
+    Ok(pkgs.iter().map(|pkg| (pkg.to_string(), Some(found.contains(*pkg)))).collect())
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    origin: PackageOrigin,
+    name: String,
+    version: String,
+    description: String,
+}
+
+fn search_arch_repo(query: &str, config: &ExecConfig, base: &str) -> Result<Vec<SearchHit>, AssistError> {
+    let client = http_client(config)?;
+    let url = format!("{base}?q={}", urlencoding::encode(query));
+    let json = send_with_retry(client.get(url), "archlinux.org package search", config)
+        .and_then(|resp| resp.json::<ArchSearch>())
+        .map_err(|e| http_error("archlinux.org package search", e, config))?;
+    Ok(json
+        .results
+        .into_iter()
+        .map(|r| SearchHit {
+            origin: PackageOrigin::Repo,
+            version: if r.pkgrel.is_empty() { r.pkgver } else { format!("{}-{}", r.pkgver, r.pkgrel) },
+            name: r.pkgname,
+            description: r.pkgdesc.unwrap_or_default(),
+        })
+        .collect())
+}
+
+fn search_aur(query: &str, config: &ExecConfig, base: &str) -> Result<Vec<SearchHit>, AssistError> {
+    let client = http_client(config)?;
+    let url = format!("{base}?v=5&type=search&arg={}", urlencoding::encode(query));
+    let json = send_with_retry(client.get(url), "AUR RPC", config)
+        .and_then(|resp| resp.json::<AurInfo>())
+        .map_err(|e| http_error("AUR RPC", e, config))?;
+    Ok(json
+        .results
+        .into_iter()
+        .map(|r| SearchHit {
+            origin: PackageOrigin::Aur,
+            name: r.name,
+            version: r.version,
+            description: r.description.unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Queries the selected registries concurrently; repo hits come first and win on duplicate names.
+fn search_packages(
+    query: &str,
+    repo: bool,
+    aur: bool,
+    config: &ExecConfig,
+    arch_url: &str,
+    aur_url: &str,
+) -> Result<Vec<SearchHit>, AssistError> {
+    let (repo_hits, aur_hits) = std::thread::scope(|scope| {
+        let repo_hits = repo.then(|| scope.spawn(|| search_arch_repo(query, config, arch_url)));
+        let aur_hits = aur.then(|| scope.spawn(|| search_aur(query, config, aur_url)));
+        let join = |handle: Option<std::thread::ScopedJoinHandle<'_, Result<Vec<SearchHit>, AssistError>>>| {
+            handle.map_or(Ok(Vec::new()), |h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(AssistError::CommandFailed("package search thread panicked".into())))
+            })
+        };
+        (join(repo_hits), join(aur_hits))
+    });
+
+    let mut seen = HashSet::new();
+    Ok(repo_hits?
+        .into_iter()
+        .chain(aur_hits?)
+        .filter(|hit| seen.insert(hit.name.clone()))
+        .collect())
+}
+
+pub fn handle_search(query: &str, repo_only: bool, aur_only: bool, config: &ExecConfig) -> Result<(), AssistError> {
+    if config.offline {
+        return Err(AssistError::CommandFailed("search needs the network (drop --offline)".into()));
+    }
+    let hits = search_packages(query, !aur_only, !repo_only, config, ARCH_SEARCH_URL, AUR_RPC_URL)?;
+    if config.json {
+        let out = serde_json::to_string_pretty(&hits)
+            .map_err(|e| AssistError::CommandFailed(format!("json output ({e})")))?;
+        println!("{out}");
+        return Ok(());
+    }
+    if hits.is_empty() {
+        println!("No packages found for '{query}'");
+    }
+    for hit in &hits {
+        let tag = match hit.origin {
+            PackageOrigin::Aur => "[aur] ",
+            _ => "[repo]",
+        };
+        println!(
+            "{tag} {} {}    {}",
+            Paint::Bold.apply(&hit.name, config.color, false),
+            hit.version,
+            Paint::Dim.apply(&hit.description, config.color, false)
+        );
+    }
+    Ok(())
+}
+
+/// One line of the `doctor` checklist.
+#[derive(Serialize)]
+struct DoctorCheck {
+    check: String,
+    ok: bool,
+    detail: String,
+}
+
+fn doctor_check(check: impl Into<String>, ok: bool, detail: impl Into<String>) -> DoctorCheck {
+    DoctorCheck {
+        check: check.into(),
+        ok,
+        detail: detail.into(),
+    }
+}
+
+/// Everything `doctor` reports. The two reachability checks run in parallel and are left out
+/// under `--offline`.
+fn doctor_checks(config: &ExecConfig) -> Vec<DoctorCheck> {
+    let mut checks = vec![doctor_check(
+        "Arch-based system",
+        on_arch_based_system(),
+        "from /etc/os-release (ID or ID_LIKE)",
+    )];
+    for program in ["pacman", "paru", "yay", "flatpak"] {
+        let found = find_in_path(program);
+        let detail = found.as_ref().map_or("not on PATH".to_string(), |path| path.display().to_string());
+        checks.push(doctor_check(format!("{program} installed"), found.is_some(), detail));
+    }
+    let key_set = std::env::var_os("OPENAI_API_KEY").is_some_and(|k| !k.is_empty());
+    let key_detail = if key_set { "set" } else { "not set; LLM fallbacks need it unless ARCH_ASSIST_BACKEND=ollama" };
+    checks.push(doctor_check("OPENAI_API_KEY", key_set, key_detail));
+    if !config.offline {
+        let (repo, aur) = std::thread::scope(|scope| {
+            let repo = scope.spawn(|| reachable("https://archlinux.org/", config));
+            let aur = scope.spawn(|| reachable(AUR_RPC_URL, config));
+            (repo.join(), aur.join())
+        });
+        for (name, result) in [("archlinux.org reachable", repo), ("AUR reachable", aur)] {
+            let result = result.unwrap_or_else(|_| Err("check panicked".to_string()));
+            let detail = result.clone().unwrap_or_else(|e| e);
+            checks.push(doctor_check(name, result.is_ok(), detail));
+        }
+    }
+    let multilib = fs::read_to_string(PACMAN_CONF)
+        .ok()
+        .map(|conf| enabled_repos(&conf).contains("multilib"));
+    let multilib_detail = match multilib {
+        Some(true) => format!("[multilib] enabled in {PACMAN_CONF}"),
+        Some(false) => format!("[multilib] commented out in {PACMAN_CONF} (needed for Steam, Wine)"),
+        None => format!("cannot read {PACMAN_CONF}"),
+    };
+    checks.push(doctor_check("multilib enabled", multilib == Some(true), multilib_detail));
+    checks
+}
+
+/// `Ok(status)` when `url` answers at all (any HTTP status), `Err(reason)` otherwise.
+fn reachable(url: &str, config: &ExecConfig) -> Result<String, String> {
+    let client = http_client(config).map_err(|e| e.to_string())?;
+    match client.head(url).send() {
+        Ok(resp) => Ok(format!("HTTP {}", resp.status().as_u16())),
+        Err(e) if e.is_timeout() => Err(format!("timed out after {}s", config.timeout)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// `doctor`: a checklist of what suggestions and package resolution depend on.
+pub fn handle_doctor(config: &ExecConfig) -> Result<(), AssistError> {
+    let checks = doctor_checks(config);
+    if config.json {
+        let out = serde_json::to_string_pretty(&checks)
+            .map_err(|e| AssistError::CommandFailed(format!("json output ({e})")))?;
+        println!("{out}");
+        return Ok(());
+    }
+    for check in &checks {
+        let mark = if check.ok {
+            Paint::Green.apply("[ok]", config.color, false)
+        } else {
+            Paint::Red.apply("[!!]", config.color, false)
+        };
+        println!("{mark} {}    {}", check.check, Paint::Dim.apply(&check.detail, config.color, false));
+    }
+    if config.offline {
+        println!("(network checks skipped with --offline)");
+    }
+    Ok(())
+}
+
+/// Fetches a registry response as raw JSON so `--json` can pass it through untouched.
+fn fetch_json(url: String, endpoint: &str, config: &ExecConfig) -> Result<serde_json::Value, AssistError> {
+    send_with_retry(http_client(config)?.get(url), endpoint, config)
+        .and_then(|resp| resp.json::<serde_json::Value>())
+        .map_err(|e| http_error(endpoint, e, config))
+}
+
+fn first_result(raw: &serde_json::Value) -> Option<serde_json::Value> {
+    raw.get("results")?.as_array()?.first().cloned()
+}
+
+/// Exact-name lookup: official repos first, then the AUR. Returns the raw result object.
+fn fetch_package_info(
+    pkg: &str,
+    config: &ExecConfig,
+    arch_url: &str,
+    aur_url: &str,
+) -> Result<Option<(PackageOrigin, serde_json::Value)>, AssistError> {
+    let url = format!("{arch_url}?name={}", urlencoding::encode(pkg));
+    if let Some(result) = first_result(&fetch_json(url, "archlinux.org package search", config)?) {
+        return Ok(Some((PackageOrigin::Repo, result)));
+    }
+    let url = format!("{aur_url}?v=5&type=info&arg={}", urlencoding::encode(pkg));
+    Ok(first_result(&fetch_json(url, "AUR RPC", config)?).map(|result| (PackageOrigin::Aur, result)))
+}
+
+fn info_rows(origin: PackageOrigin, raw: serde_json::Value) -> Result<Vec<(&'static str, String)>, AssistError> {
+    let parse_err = |e: serde_json::Error| AssistError::CommandFailed(format!("package info ({e})"));
+    let list = |deps: Vec<String>| if deps.is_empty() { "None".to_string() } else { deps.join(", ") };
+    Ok(match origin {
+        PackageOrigin::Aur => {
+            let info: AurResult = serde_json::from_value(raw).map_err(parse_err)?;
+            vec![
+                ("Name", info.name),
+                ("Repository", "aur".to_string()),
+                ("Version", info.version),
+                ("Description", info.description.unwrap_or_default()),
+                ("Depends On", list(info.depends)),
+                ("Votes", info.num_votes.unwrap_or(0).to_string()),
+                ("Last Updated", info.last_modified.map(iso8601_utc).unwrap_or_default()),
+            ]
+        }
+        _ => {
+            let info: ArchResult = serde_json::from_value(raw).map_err(parse_err)?;
+            vec![
+                ("Name", info.pkgname),
+                ("Repository", info.repo.unwrap_or_default()),
+                ("Version", format!("{}-{}", info.pkgver, info.pkgrel)),
+                ("Description", info.pkgdesc.unwrap_or_default()),
+                ("Depends On", list(info.depends)),
+                ("Download Size", info.compressed_size.map(human_size).unwrap_or_default()),
+                ("Installed Size", info.installed_size.map(human_size).unwrap_or_default()),
+            ]
+        }
+    })
+}
+
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", units[unit])
+    }
+}
+
+pub fn handle_info(pkg: &str, config: &ExecConfig) -> Result<(), AssistError> {
+    if config.offline {
+        return Err(AssistError::CommandFailed("info needs the network (drop --offline)".into()));
+    }
+    let (origin, raw) = fetch_package_info(pkg, config, ARCH_SEARCH_URL, AUR_RPC_URL)?
+        .ok_or_else(|| AssistError::CommandFailed(format!("{pkg}: not found in the repos or the AUR")))?;
+    if config.json {
+        let out = serde_json::to_string_pretty(&raw)
+            .map_err(|e| AssistError::CommandFailed(format!("json output ({e})")))?;
+        println!("{out}");
+        return Ok(());
+    }
+    for (key, value) in info_rows(origin, raw)? {
+        println!("{}: {value}", Paint::Bold.apply(&format!("{key:<15}"), config.color, false));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ChatMessage {
+    role: String,
+    content: Vec<ChatContent>,
+}
+
+#[derive(Serialize)]
+struct ChatContent {
+    #[serde(rename = "type")]
+    kind: String,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: LlmMessage,
+}
+
+#[derive(Deserialize)]
+struct LlmMessage {
+    content: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: LlmMessage,
+}
+
+#[derive(Deserialize)]
+struct ArchSearch {
+    results: Vec<ArchResult>,
+}
+
+#[derive(Deserialize)]
+struct ArchResult {
+    pkgname: String,
+    #[serde(default)]
+    pkgver: String,
+    #[serde(default)]
+    pkgrel: String,
+    #[serde(default)]
+    pkgdesc: Option<String>,
+    #[serde(default)]
+    repo: Option<String>,
+    #[serde(default)]
+    depends: Vec<String>,
+    #[serde(default)]
+    compressed_size: Option<u64>,
+    #[serde(default)]
+    installed_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct AurInfo {
+    #[serde(default)]
+    results: Vec<AurResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AurResult {
+    name: String,
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    depends: Vec<String>,
+    #[serde(default)]
+    num_votes: Option<u32>,
+    #[serde(default)]
+    last_modified: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::cell::RefCell;
+    use std::net::TcpListener;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn validate_rejects_sudo_wrapped_programs_outside_allowlist() {
+        assert!(validate("sudo reboot", &test_config()).is_err());
+        assert!(validate("sudo rm /etc/hosts", &test_config()).is_err());
+        assert!(validate("doas reboot", &test_config()).is_err());
+        assert!(validate("sudo", &test_config()).is_err());
+    }
+
+    #[test]
+    fn validate_accepts_sudo_wrapped_allowed_programs() {
+        assert!(validate("sudo pacman -Syu", &test_config()).is_ok());
+        assert!(validate("sudo systemctl restart NetworkManager", &test_config()).is_ok());
+    }
+
+    #[test]
+    fn forbidden_scan_matches_dd_and_mkfs_as_programs_only() {
+        assert!(scan_forbidden("sudo useradd alice").is_ok());
+        assert!(scan_forbidden("pacman -S mkfs-tools").is_ok());
+        assert!(scan_forbidden("dd if=/dev/zero of=/dev/sda").is_err());
+        assert!(scan_forbidden("sudo mkfs.ext4 /dev/sda1").is_err());
+        assert!(validate("dd if=/dev/zero", &test_config()).is_err());
+    }
+
+    #[test]
+    fn forbidden_scan_catches_rm_recursive_force_variants() {
+        assert!(scan_forbidden("rm -rf /").is_err());
+        assert!(scan_forbidden("rm -fr /").is_err());
+        assert!(scan_forbidden("rm  -rf /").is_err());
+        assert!(scan_forbidden("rm -r -f ~").is_err());
+        assert!(scan_forbidden("sudo rm --recursive --force /").is_err());
+        assert!(scan_forbidden("rm -R -f /tmp/x").is_err());
+        assert!(scan_forbidden("rm -r build").is_ok());
+    }
+
+    #[test]
+    fn offline_guard_blocks_refresh_and_sync_installs() {
+        assert!(is_network_pkg_op("paru -Syu"));
+        assert!(is_network_pkg_op("paru -Sy"));
+        assert!(is_network_pkg_op("pacman -Sy"));
+        assert!(is_network_pkg_op("sudo pacman -Sy"));
+        assert!(is_network_pkg_op("sudo pacman -Syyu --noconfirm"));
+        assert!(is_network_pkg_op("doas pacman -Syu"));
+        assert!(is_network_pkg_op("sudo pacman -S --needed firefox"));
+        assert!(is_network_pkg_op("sudo pacman --sync --refresh"));
+        assert!(is_network_pkg_op("paru"));
+        assert!(is_network_pkg_op("sudo pacman -U https://archive.archlinux.org/packages/f/foo.pkg.tar.zst"));
+    }
+
+    #[test]
+    fn provides_syncs_the_file_database_first_when_needed() {
+        let dir = std::env::temp_dir().join(format!("arch-assist-test-sync-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("core.db"), "").unwrap();
+        let before = files_db_synced(&dir);
+        fs::write(dir.join("core.files"), "").unwrap();
+        let after = files_db_synced(&dir);
+        let _ = fs::remove_dir_all(&dir);
+        assert!(!before && after);
+
+        let resolver = HttpResolver { config: &test_config() };
+        let cmds = builtin_translate("what provides libGL.so.1", &test_config(), &HashSet::new(), &resolver)
+            .unwrap()
+            .unwrap();
+        assert!(cmds.iter().any(|s| s.cmd == "pacman -F libGL.so.1"));
+        assert!(is_network_pkg_op("sudo pacman -Fy"));
+        assert!(!is_network_pkg_op("pacman -F libGL.so.1"));
+        let prompt = "which package has firewall-cmd";
+        let cmds = builtin_translate(prompt, &test_config(), &HashSet::new(), &resolver).unwrap().unwrap();
+        assert!(cmds.iter().any(|s| s.cmd == "pacman -F firewall-cmd"));
+    }
+
+    #[test]
+    fn offline_allows_installing_local_package_files() {
+        let offline = ExecConfig {
+            offline: true,
+            ..test_config()
+        };
+        let local = |cmd: &str| Suggestion {
+            cmd: cmd.to_string(),
+            reason: "install local package".into(),
+        };
+        assert!(ensure_offline_ok(&local("sudo pacman -U ./foo.pkg.tar.zst"), &offline).is_ok());
+        assert!(ensure_offline_ok(&local("sudo pacman --upgrade /tmp/foo.pkg.tar.zst --noconfirm"), &offline).is_ok());
+        assert!(ensure_offline_ok(&local("sudo pacman -U https://example.org/foo.pkg.tar.zst"), &offline).is_err());
+        assert!(ensure_offline_ok(&local("sudo pacman -S foo"), &offline).is_err());
+        assert!(ensure_offline_ok(&local("sudo pacman -Sy"), &offline).is_err());
+    }
+
+    #[test]
+    fn offline_guard_allows_local_operations() {
+        assert!(!is_network_pkg_op("sudo pacman -Sc"));
+        assert!(!is_network_pkg_op("pacman -Ss firefox"));
+        assert!(!is_network_pkg_op("pacman -Qi firefox"));
+        assert!(!is_network_pkg_op("sudo pacman -Rsn firefox"));
+        assert!(!is_network_pkg_op("systemctl restart bluetooth"));
+    }
+
+    fn test_config() -> ExecConfig {
+        ExecConfig {
+            dry_run: false,
+            auto: false,
+            offline: false,
+            yes: false,
+            prefer_paru: false,
+            no_sudo: false,
+            use_doas: false,
+            capture: false,
+            quiet: false,
+            color: false,
+            interactive: false,
+            history: false,
+            suggestions: false,
+            verbose: false,
+            json: false,
+            reinstall: false,
+            strict_distro: false,
+            explain_before_run: false,
+            allow_pipes: false,
+            prefer_flatpak: false,
+            notify: false,
+            snapshot: false,
+            pager: false,
+            non_interactive: false,
+            assume_origin: None,
+            max_commands: None,
+            model_override: None,
+            model: None,
+            backend: Backend::OpenAi,
+            timeout: 5,
+            retries: 0,
+            package_cache_ttl: Duration::ZERO,
+            llm_cache_ttl: Duration::ZERO,
+            system_prompt: None,
+            temperature: ExecConfig::DEFAULT_TEMPERATURE,
+            max_tokens: ExecConfig::DEFAULT_MAX_TOKENS,
+            aur_helper: Some("paru".to_string()),
+            extra_allowed: Vec::new(),
+            extra_forbidden: Vec::new(),
+            extra_aur_packages: Vec::new(),
+            extra_pacman_args: Vec::new(),
+            sudo_askpass: None,
+        }
+    }
+
+    /// Serves canned JSON per path prefix and records every request path it sees.
+    fn mock_server(routes: Vec<(&'static str, &'static str)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let routes = routes.clone();
+                let log = Arc::clone(&log);
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 4096];
+                    let n = stream.read(&mut buf).unwrap_or(0);
+                    let request = String::from_utf8_lossy(&buf[..n]);
+                    let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+                    let body = routes
+                        .iter()
+                        .find(|(prefix, _)| path.starts_with(prefix))
+                        .map(|(_, body)| *body)
+                        .unwrap_or("{}");
+                    log.lock().unwrap().push(path);
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                });
+            }
+        });
+        (base, seen)
+    }
+
+    #[test]
+    fn lookup_queries_both_registries_and_prefers_repo() {
+        let (base, seen) = mock_server(vec![
+            ("/repo", r#"{"results":[{"pkgname":"firefox"}]}"#),
+            ("/aur", r#"{"resultcount":1,"results":[{"Name":"firefox"}]}"#),
+        ]);
+        let origin = lookup_origin(
+            "firefox",
+            &test_config(),
+            &format!("{base}/repo"),
+            &format!("{base}/aur"),
+        )
+        .unwrap();
+
+        assert!(matches!(origin, PackageOrigin::Repo));
+        let seen = seen.lock().unwrap();
+        assert!(seen.iter().any(|p| p.starts_with("/repo")));
+        assert!(seen.iter().any(|p| p.starts_with("/aur")));
+    }
+
+    #[test]
+    fn pacman_conf_sections_report_enabled_repos() {
+        let conf = "[options]\nHoldPkg = pacman glibc\n\n#[core-testing]\n#Include = /etc/pacman.d/mirrorlist\n\n\
+[core]\nInclude = /etc/pacman.d/mirrorlist\n\n[extra]\nInclude = /etc/pacman.d/mirrorlist\n\n\
+#[multilib]\n#Include = /etc/pacman.d/mirrorlist\n";
+        let repos = enabled_repos(conf);
+        assert_eq!(repos, HashSet::from(["core".to_string(), "extra".to_string()]));
+        assert!(!repos.contains("multilib"));
+    }
+
+    #[test]
+    fn lookup_falls_back_to_aur_when_repo_is_empty() {
+        let (base, _) = mock_server(vec![
+            ("/repo", r#"{"results":[]}"#),
+            ("/aur", r#"{"resultcount":1,"results":[{"Name":"brave-bin"}]}"#),
+        ]);
+        let origin = lookup_origin(
+            "brave-bin",
+            &test_config(),
+            &format!("{base}/repo"),
+            &format!("{base}/aur"),
+        )
+        .unwrap();
+        assert!(matches!(origin, PackageOrigin::Aur));
+    }
+
+    #[test]
+    fn doctor_skips_network_checks_offline() {
+        let offline = ExecConfig {
+            offline: true,
+            ..test_config()
+        };
+        let names: Vec<String> = doctor_checks(&offline).into_iter().map(|c| c.check).collect();
+        assert_eq!(
+            names,
+            [
+                "Arch-based system",
+                "pacman installed",
+                "paru installed",
+                "yay installed",
+                "flatpak installed",
+                "OPENAI_API_KEY",
+                "multilib enabled",
+            ]
+        );
+        let (base, _) = mock_server(vec![]);
+        assert!(reachable(&base, &test_config()).is_ok());
+        assert!(reachable("http://127.0.0.1:1/", &test_config()).is_err());
+    }
+
+    #[test]
+    fn aur_batch_asks_once_for_every_package() {
+        let (base, seen) = mock_server(vec![(
+            "/aur",
+            r#"{"resultcount":2,"results":[{"Name":"yay"},{"Name":"brave-bin"}]}"#,
+        )]);
+        let found = check_aur_batch(&["yay", "firefox", "brave-bin"], &test_config(), &format!("{base}/aur")).unwrap();
+        assert_eq!(found.get("yay"), Some(&Some(true)));
+        assert_eq!(found.get("brave-bin"), Some(&Some(true)));
+        assert_eq!(found.get("firefox"), Some(&Some(false)));
+        let paths = seen.lock().unwrap().clone();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].contains("arg[]=yay&arg[]=firefox&arg[]=brave-bin"), "{}", paths[0]);
+        assert_eq!(check_aur("yay", &test_config(), &format!("{base}/aur")).unwrap(), Some(true));
+    }
+
+    /// Answers every request with `429 Too Many Requests`.
+    fn rate_limited_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                let body = r#"{"error":"Rate limit reached"}"#;
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 429 Too Many Requests\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+Connection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+        base
+    }
+
+    #[test]
+    fn rate_limited_aur_leaves_origin_undetermined() {
+        let aur = rate_limited_server();
+        let (repo, _) = mock_server(vec![("/repo", r#"{"results":[]}"#)]);
+        assert_eq!(check_aur("spotify", &test_config(), &aur).unwrap(), None);
+        let origin = lookup_origin("spotify", &test_config(), &format!("{repo}/repo"), &aur).unwrap();
+        assert!(matches!(origin, PackageOrigin::Undetermined));
+
+        // Falls back to the AUR guess list instead of sending everything to pacman
+        let cmds = plan_install(&["spotify", "some-tool"], "-S --needed", &test_config(), |_| {
+            Ok(PackageOrigin::Undetermined)
+        })
+        .unwrap();
+        assert_eq!(cmds, ["paru -S --needed spotify", "sudo pacman -S --needed some-tool"]);
+    }
+
+    #[test]
+    fn plan_install_groups_repo_and_aur_packages() {
+        let config = test_config();
+        let cmds = plan_install(&["firefox", "brave-bin", "vlc"], "-S --needed", &config, |pkg| {
+            Ok(if pkg == "brave-bin" { PackageOrigin::Aur } else { PackageOrigin::Repo })
+        })
+        .unwrap();
+        assert_eq!(
+            cmds,
+            vec!["sudo pacman -S --needed firefox vlc", "paru -S --needed brave-bin"]
+        );
+    }
+
+    #[test]
+    fn batch_resolution_runs_lookups_concurrently_in_order() {
+        let pkgs = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let slow = |pkg: &str| {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(if pkg < "e" { PackageOrigin::Repo } else { PackageOrigin::Aur })
+        };
+        let started = std::time::Instant::now();
+        let origins = resolve_all(&pkgs, slow).unwrap();
+        // Serially this takes 1.6s; eight workers finish in about one lookup
+        assert!(started.elapsed() < Duration::from_millis(800), "{:?}", started.elapsed());
+        let names: Vec<String> = origins.iter().map(|o| format!("{o:?}")).collect();
+        assert_eq!(names, ["Repo", "Repo", "Repo", "Repo", "Aur", "Aur", "Aur", "Aur"]);
+
+        let cmds = plan_install(&pkgs, "-S --needed", &test_config(), slow).unwrap();
+        assert_eq!(cmds, ["sudo pacman -S --needed a b c d", "paru -S --needed e f g h"]);
+
+        let failing = |pkg: &str| match pkg {
+            "b" | "d" => Err(AssistError::Network(format!("lookup {pkg}"))),
+            _ => Ok(PackageOrigin::Repo),
+        };
+        assert!(resolve_all(&pkgs, failing).is_err_and(|e| e.to_string().contains("lookup b")));
+    }
+
+    #[test]
+    fn plan_install_keeps_group_order_and_uses_aur_heuristic() {
+        let config = test_config();
+        let cmds = plan_install(&["spotify-bin", "neovim"], "-S --needed", &config, |pkg| {
+            Ok(if pkg == "neovim" { PackageOrigin::Repo } else { PackageOrigin::Unknown })
+        })
+        .unwrap();
+        assert_eq!(cmds, vec!["paru -S --needed spotify-bin", "sudo pacman -S --needed neovim"]);
+    }
+
+    #[test]
+    fn disabled_repos_are_skipped_but_never_silently() {
+        let repos = ["core-testing".to_string(), "core".to_string()];
+        assert_eq!(pick_repo(&repos, |r| r == "core").as_deref(), Some("core"));
+        assert_eq!(pick_repo(&repos, |_| false).as_deref(), Some("core-testing"));
+        assert_eq!(pick_repo(&[], |_| true), None);
+
+        let origin = |pkg: &str| Ok(if pkg == "steam" { PackageOrigin::RepoDisabled } else { PackageOrigin::Repo });
+        let cmds = plan_install(&["steam", "vlc"], "-S --needed", &test_config(), origin).unwrap();
+        assert_eq!(cmds, ["sudo pacman -S --needed vlc"]);
+        let err = plan_install(&["steam"], "-S --needed", &test_config(), origin).unwrap_err();
+        assert!(err.to_string().contains("nothing to install: steam"), "{err}");
+    }
+
+    #[test]
+    fn assumed_origin_skips_every_lookup() {
+        // Offline would otherwise answer Offline (or Repo from the sync DB)
+        let config = ExecConfig {
+            assume_origin: Some(PackageOrigin::Aur),
+            offline: true,
+            ..test_config()
+        };
+        assert!(matches!(resolve_package("firefox", &config), Ok(PackageOrigin::Aur)));
+        let config = ExecConfig {
+            assume_origin: Some(PackageOrigin::Repo),
+            ..config
+        };
+        assert!(matches!(resolve_package("brave-bin", &config), Ok(PackageOrigin::Repo)));
+    }
+
+    #[test]
+    fn aur_guess_list_comes_from_the_data_file_and_config() {
+        let config = test_config();
+        for pkg in ["google-chrome", "discord-canary", "teams-for-linux", "protonvpn", "rustdesk-bin", "foo-git"] {
+            assert!(is_probably_aur(pkg, &config), "{pkg}");
+        }
+        for pkg in ["firefox", "neovim", "# Browsers", ""] {
+            assert!(!is_probably_aur(pkg, &config), "{pkg:?}");
+        }
+        let extended = ExecConfig {
+            extra_aur_packages: vec!["my-internal-tool".to_string()],
+            ..test_config()
+        };
+        assert!(is_probably_aur("my-internal-tool", &extended));
+        assert!(!is_probably_aur("my-internal-tool", &config));
+    }
+
+    #[test]
+    fn split_package_list_handles_commas_and_conjunctions() {
+        assert_eq!(split_package_list("firefox, vlc and neovim"), vec!["firefox", "vlc", "neovim"]);
+    }
+
+    #[test]
+    fn iso8601_formats_epoch_and_leap_day() {
+        assert_eq!(iso8601_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601_utc(951_827_696), "2000-02-29T12:34:56Z");
+    }
+
+    #[test]
+    fn history_line_round_trips_fields() {
+        let entry = parse_history_line("2024-01-02T03:04:05Z\t0\tinstall vlc\tsudo pacman -S vlc").unwrap();
+        assert_eq!(entry.exit, "0");
+        assert_eq!(entry.prompt, "install vlc");
+        assert_eq!(entry.cmd, "sudo pacman -S vlc");
+        assert!(parse_history_line("garbage").is_none());
+    }
+
+    #[test]
+    fn step_choice_defaults_to_no_and_rejects_unknown() {
+        assert_eq!(parse_step_choice("\n"), Some(StepChoice::No));
+        assert_eq!(parse_step_choice("A\n"), Some(StepChoice::All));
+        assert_eq!(parse_step_choice("quit"), Some(StepChoice::Quit));
+        assert_eq!(parse_step_choice("e"), Some(StepChoice::Edit));
+        assert_eq!(parse_step_choice("maybe"), None);
+    }
+
+    /// Records every command instead of spawning it; always reports success.
+    #[derive(Default)]
+    struct MockRunner {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(&self, cmd: &str) -> Result<ExitStatus, AssistError> {
+            self.calls.borrow_mut().push(cmd.to_string());
+            Ok(ExitStatus::from_raw(0))
+        }
+    }
+
+    fn test_state(config: ExecConfig) -> AppState {
+        AppState {
+            config,
+            installed_file: std::env::temp_dir().join("arch-assist-test-installed.txt"),
+            installed: HashSet::new(),
+            prompt: None,
+        }
+    }
+
+    #[test]
+    fn installed_state_records_every_target_but_no_flags() {
+        let mut state = test_state(ExecConfig {
+            yes: true,
+            ..test_config()
+        });
+        state.installed_file = std::env::temp_dir().join(format!("arch-assist-installed-{}.txt", std::process::id()));
+        let runner = MockRunner::default();
+        run("sudo pacman -S --needed vlc gimp --noconfirm", &mut state, &runner).unwrap();
+        run("paru -S --needed brave-bin --noconfirm", &mut state, &runner).unwrap();
+        let mut installed: Vec<&str> = state.installed.iter().map(String::as_str).collect();
+        installed.sort();
+        assert_eq!(installed, ["brave-bin", "gimp", "vlc"]);
+        run("sudo pacman -Rsn vlc gimp --noconfirm", &mut state, &runner).unwrap();
+        run("sudo pacman -Ss mpv", &mut state, &runner).unwrap();
+        assert_eq!(state.installed, HashSet::from(["brave-bin".to_string()]));
+        let _ = fs::remove_file(&state.installed_file);
+    }
+
+    #[test]
+    fn auto_runs_builtin_suggestions_in_order() {
+        let mut state = test_state(ExecConfig {
+            auto: true,
+            yes: true,
+            ..test_config()
+        });
+        let runner = MockRunner::default();
+        handle_prompt("fix time", &mut state, &runner).unwrap();
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec!["sudo timedatectl set-ntp true", "timedatectl status"]
+        );
+    }
+
+    #[test]
+    fn auto_respects_doas_and_dry_run() {
+        let mut state = test_state(ExecConfig {
+            auto: true,
+            yes: true,
+            use_doas: true,
+            ..test_config()
+        });
+        let runner = MockRunner::default();
+        handle_prompt("enable sshd and start it", &mut state, &runner).unwrap();
+        assert_eq!(*runner.calls.borrow(), vec!["doas systemctl enable --now sshd"]);
+
+        state.config.dry_run = true;
+        let runner = MockRunner::default();
+        handle_prompt("fix time", &mut state, &runner).unwrap();
+        assert!(runner.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn prompt_script_skips_blanks_and_comments() {
+        let mut state = test_state(ExecConfig {
+            auto: true,
+            yes: true,
+            ..test_config()
+        });
+        let runner = MockRunner::default();
+        let script = "# new laptop\n\nfix time\n   \n  # audio next\nenable sshd and start\n";
+        handle_prompt_script(script, &mut state, &runner).unwrap();
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec!["sudo timedatectl set-ntp true", "timedatectl status", "sudo systemctl enable --now sshd"]
+        );
+    }
+
+    #[test]
+    fn install_size_helpers_read_pacman_output() {
+        let batch = [
+            Suggestion {
+                cmd: "sudo pacman -S --needed firefox vlc --noconfirm".to_string(),
+                reason: "install".into(),
+            },
+            Suggestion {
+                cmd: "paru -S --needed brave-bin".to_string(),
+                reason: "install".into(),
+            },
+            Suggestion {
+                cmd: "sudo pacman -Syu".to_string(),
+                reason: "upgrade".into(),
+            },
+            Suggestion {
+                cmd: "pacman -Ss firefox".to_string(),
+                reason: "search".into(),
+            },
+        ];
+        assert_eq!(install_targets(&batch), ["firefox", "vlc"]);
+
+        let (names, download) = parse_print_format("firefox 70000000\nlibvlc 1024\nvlc 2048\n");
+        assert_eq!(names, ["firefox", "libvlc", "vlc"]);
+        assert_eq!(download, 70_003_072);
+
+        let info = "Name            : firefox\nInstalled Size  : 2.00 MiB\n\n\
+Name            : vlc\nInstalled Size  : 512.00 KiB\n";
+        assert_eq!(installed_size_total(info), 2 * 1024 * 1024 + 512 * 1024);
+    }
+
+    #[test]
+    fn llm_lines_carry_optional_reasons() {
+        assert_eq!(
+            split_llm_reason("sudo pacman -S vlc\t# install the VLC player"),
+            ("sudo pacman -S vlc", Some("install the VLC player"))
+        );
+        assert_eq!(
+            split_llm_reason("systemctl status bluetooth  #check the service"),
+            ("systemctl status bluetooth", Some("check the service"))
+        );
+        assert_eq!(split_llm_reason("timedatectl status"), ("timedatectl status", None));
+        assert_eq!(split_llm_reason("timedatectl status\t#"), ("timedatectl status", None));
+    }
+
+    #[test]
+    fn dry_run_plan_groups_network_and_local_commands() {
+        let plan = plan_summary(&[
+            Suggestion {
+                cmd: "sudo pacman -Syu".to_string(),
+                reason: "upgrade".into(),
+            },
+            Suggestion {
+                cmd: "systemctl --failed --no-pager".to_string(),
+                reason: "list failed units".into(),
+            },
+            Suggestion {
+                cmd: "paru -S --needed brave-bin".to_string(),
+                reason: "install".into(),
+            },
+        ]);
+        assert!(plan.contains("Plan: 3 commands would run"));
+        let network = plan.find("network (2)").unwrap();
+        let local = plan.find("local (1)").unwrap();
+        assert!(network < plan.find("sudo pacman -Syu").unwrap());
+        assert!(plan.find("paru -S --needed brave-bin").unwrap() < local);
+        assert!(plan.contains("--offline would block the 2 network command(s)"));
+    }
+
+    #[test]
+    fn suggestions_do_not_run_without_auto() {
+        let mut state = test_state(test_config());
+        let runner = MockRunner::default();
+        handle_prompt("fix bluetooth", &mut state, &runner).unwrap();
+        assert!(runner.calls.borrow().is_empty());
+    }
+
+    /// Answers from a fixed table; anything else is Unknown.
+    struct StaticResolver(HashMap<&'static str, PackageOrigin>);
+
+    impl PackageResolver for StaticResolver {
+        fn resolve(&self, pkg: &str) -> Result<PackageOrigin, AssistError> {
+            Ok(self.0.get(pkg).copied().unwrap_or(PackageOrigin::Unknown))
+        }
+    }
+
+    #[test]
+    fn build_install_command_picks_installer_by_origin() {
+        // reinstall skips the local `pacman -Q` probe
+        let config = ExecConfig {
+            reinstall: true,
+            ..test_config()
+        };
+        let resolver = StaticResolver(HashMap::from([
+            ("vlc", PackageOrigin::Repo),
+            ("spotify", PackageOrigin::Aur),
+        ]));
+        let build = |pkg| build_install_command(pkg, "-S --needed", &config, &resolver).unwrap();
+        assert_eq!(build("vlc").as_deref(), Some("sudo pacman -S --needed vlc"));
+        assert_eq!(build("spotify").as_deref(), Some("paru -S --needed spotify"));
+        assert_eq!(build("zoom-bin").as_deref(), Some("paru -S --needed zoom-bin"));
+        assert_eq!(build("htop").as_deref(), Some("sudo pacman -S --needed htop"));
+    }
+
+    #[test]
+    fn reinstall_drops_needed_and_routes_by_origin() {
+        let config = ExecConfig {
+            extra_pacman_args: vec!["--needed".into()],
+            ..test_config()
+        };
+        let resolver = StaticResolver(HashMap::from([("vlc", PackageOrigin::Repo), ("spotify", PackageOrigin::Aur)]));
+        let installed = HashSet::from(["vlc".to_string()]);
+        let cmds: Vec<String> = builtin_translate("reinstall vlc and spotify", &config, &installed, &resolver)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.cmd)
+            .collect();
+        assert_eq!(cmds, ["sudo pacman -S vlc", "paru -S spotify"]);
+    }
+
+    #[test]
+    fn hold_ignores_packages_for_one_upgrade() {
+        let config = ExecConfig {
+            yes: true,
+            ..test_config()
+        };
+        let prompt = "ignore linux and nvidia on upgrade";
+        let cmds: Vec<String> = builtin_translate(prompt, &config, &HashSet::new(), &ExampleResolver)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.cmd)
+            .collect();
+        assert_eq!(cmds[0], "sudo pacman -Syu --noconfirm --ignore linux,nvidia");
+        assert!(cmds[1].ends_with("IgnorePkg = linux nvidia"), "{}", cmds[1]);
+        assert!(cmds.iter().all(|c| validate(c, &config).is_ok()));
+    }
+
+    #[test]
+    fn rewrite_install_splits_llm_pacman_command_by_origin() {
+        let config = ExecConfig {
+            reinstall: true,
+            ..test_config()
+        };
+        let resolver = StaticResolver(HashMap::from([("spotify", PackageOrigin::Aur)]));
+        let cmds =
+            rewrite_install_with_resolution("sudo pacman -S spotify neovim".into(), &config, &resolver).unwrap();
+        assert_eq!(cmds, vec!["paru -S spotify", "sudo pacman -S neovim"]);
+        let query = rewrite_install_with_resolution("pacman -Ss spotify".into(), &config, &resolver).unwrap();
+        assert_eq!(query, vec!["pacman -Ss spotify"]);
+    }
+
+    #[test]
+    fn near_matches_rank_by_edit_distance() {
+        let candidates: Vec<String> = ["firefox-developer-edition", "firefox", "firefoxpwa", "fire"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(edit_distance("firefx", "firefox"), 1);
+        assert_eq!(near_matches("firefx", &candidates, 2), vec!["firefox", "fire"]);
+        assert!(!near_matches("firefox", &candidates, 5).contains(&"firefox".to_string()));
+    }
+
+    #[test]
+    fn search_merges_registries_and_drops_duplicate_names() {
+        let (base, _) = mock_server(vec![
+            (
+                "/repo",
+                r#"{"results":[{"pkgname":"neovim","pkgver":"0.10.0","pkgrel":"1","pkgdesc":"Vim fork"}]}"#,
+            ),
+            (
+                "/aur",
+                r#"{"resultcount":2,"results":[{"Name":"neovim","Version":"0.9"},{"Name":"neovim-git","Version":"0.11.r1-1","Description":null}]}"#,
+            ),
+        ]);
+        let (repo, aur) = (format!("{base}/repo"), format!("{base}/aur"));
+        let hits = search_packages("neovim", true, true, &test_config(), &repo, &aur).unwrap();
+        let names: Vec<_> = hits.iter().map(|h| (h.name.as_str(), h.version.as_str())).collect();
+        assert_eq!(names, vec![("neovim", "0.10.0-1"), ("neovim-git", "0.11.r1-1")]);
+        assert!(matches!(hits[1].origin, PackageOrigin::Aur));
+
+        let aur_only = search_packages("neovim", false, true, &test_config(), &repo, &aur).unwrap();
+        assert_eq!(aur_only.len(), 2);
+    }
+
+    #[test]
+    fn info_falls_back_to_aur_and_formats_rows() {
+        let (base, _) = mock_server(vec![
+            ("/repo", r#"{"results":[]}"#),
+            (
+                "/aur",
+                r#"{"resultcount":1,"results":[{"Name":"yay","Version":"12.3.5-1","Depends":["pacman","git"],"NumVotes":2000,"LastModified":0}]}"#,
+            ),
+        ]);
+        let (origin, raw) =
+            fetch_package_info("yay", &test_config(), &format!("{base}/repo"), &format!("{base}/aur"))
+                .unwrap()
+                .unwrap();
+        assert!(matches!(origin, PackageOrigin::Aur));
+        let rows = info_rows(origin, raw).unwrap();
+        assert!(rows.contains(&("Depends On", "pacman, git".to_string())));
+        assert!(rows.contains(&("Votes", "2000".to_string())));
+        assert!(rows.contains(&("Last Updated", "1970-01-01T00:00:00Z".to_string())));
+        assert_eq!(human_size(1536), "1.5 KiB");
+    }
+
+    #[test]
+    fn chat_url_honors_base_override() {
+        assert_eq!(chat_completions_url(OPENAI_BASE_URL), "https://api.openai.com/v1/chat/completions");
+        assert_eq!(
+            chat_completions_url("http://localhost:8080/v1/"),
+            "http://localhost:8080/v1/chat/completions"
+        );
+        assert!(is_local_url("http://localhost:8080/v1"));
+        assert!(is_local_url("http://127.0.0.1/v1"));
+        assert!(is_local_url("http://[::1]:8000/v1"));
+        assert!(!is_local_url("https://my-proxy.example.com/v1"));
+        assert!(!is_local_url("https://localhost.example.com/v1"));
+        // OLLAMA_HOST goes through the same check under --offline
+        assert!(is_local_url(OLLAMA_DEFAULT_HOST));
+        assert!(is_local_url("127.0.0.1:11434"));
+        assert!(!is_local_url("http://gpu-box.lan:11434"));
+    }
+
+    #[test]
+    fn llm_cache_key_depends_on_every_part() {
+        let key = llm_cache_key("gpt-4o-mini", "system", "fix wifi");
+        assert_eq!(key, llm_cache_key("gpt-4o-mini", "system", "fix wifi"));
+        assert_eq!(key.len(), 16);
+        assert_ne!(key, llm_cache_key("llama3.1", "system", "fix wifi"));
+        assert_ne!(key, llm_cache_key("gpt-4o-mini", "system", "fix sound"));
+        // Part boundaries matter: ("ab", "c") must not collide with ("a", "bc")
+        assert_ne!(llm_cache_key("m", "ab", "c"), llm_cache_key("m", "a", "bc"));
+    }
+
+    #[test]
+    fn failing_command_passes_its_exit_code_through() {
+        struct FailingRunner;
+        impl CommandRunner for FailingRunner {
+            fn run(&self, _cmd: &str) -> Result<ExitStatus, AssistError> {
+                // Raw wait status: exit code 7 lives in the high byte
+                Ok(ExitStatus::from_raw(7 << 8))
+            }
+        }
+        let mut state = test_state(test_config());
+        let err = run("pacman -Qi nothere", &mut state, &FailingRunner).unwrap_err();
+        assert_eq!(err.exit_code(), 7);
+        assert!(err.to_string().contains("pacman -Qi nothere exited with"));
+    }
+
+    #[test]
+    fn error_kinds_map_to_distinct_exit_codes() {
+        assert_eq!(AssistError::CommandFailed("pacman".into()).exit_code(), 1);
+        assert_eq!(AssistError::Unsafe("rm -rf /".into()).exit_code(), 2);
+        assert_eq!(AssistError::Network("llm call".into()).exit_code(), 3);
+    }
+
+    #[test]
+    fn retry_recovers_from_transient_503_but_not_401() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (i, mut stream) in listener.incoming().flatten().enumerate() {
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let path = String::from_utf8_lossy(&buf[..n]).split_whitespace().nth(1).unwrap_or("").to_string();
+                let status = match (path.as_str(), i) {
+                    ("/flaky", 0) => "503 Service Unavailable",
+                    ("/auth", _) => "401 Unauthorized",
+                    _ => "200 OK",
+                };
+                let _ = write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            }
+        });
+        let config = ExecConfig {
+            retries: 2,
+            ..test_config()
+        };
+        let client = http_client(&config).unwrap();
+        let resp = send_with_retry(client.get(format!("{base}/flaky")), "test", &config).unwrap();
+        assert_eq!(resp.status(), 200);
+        let resp = send_with_retry(client.get(format!("{base}/auth")), "test", &config).unwrap();
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[test]
+    fn system_prompt_override_reaches_request_body_with_rules() {
+        let config = ExecConfig {
+            system_prompt: Some("Prefer flatpak for GUI apps.".into()),
+            ..test_config()
+        };
+        let system = build_system_prompt("none", &config);
+        let body = serde_json::to_string(&openai_request(&system, "install gimp", &config)).unwrap();
+        assert!(body.contains("Prefer flatpak for GUI apps."));
+        assert!(body.contains("Never use dangerous operators"));
+        assert!(!body.contains("You are an Arch Linux expert"));
+    }
+
+    #[test]
+    fn fenced_llm_output_yields_clean_commands() {
+        let (cmds, reasons) =
+            parse_llm_commands("```bash\nsudo pacman -S --needed vlc\t# media player\nlaunch vlc\n```\n");
+        assert_eq!(cmds, ["sudo pacman -S --needed vlc", "launch vlc"]);
+        assert_eq!(reasons["sudo pacman -S --needed vlc"], "media player");
+        let (cmds, _) = parse_llm_commands("  ```sh\n`systemctl --failed`\n  ```");
+        assert_eq!(cmds, ["systemctl --failed"]);
+        for cmd in &cmds {
+            assert!(validate(cmd, &test_config()).is_ok());
+        }
+    }
+
+    #[test]
+    fn llm_output_loses_prompts_comments_and_prose() {
+        let content = "Here's how to update:\n# refresh and upgrade everything\n$ sudo pacman -Syu\n\
+                       # systemctl --failed\nFirst, check the result.\n$ sudo pacman -Syu";
+        let (cmds, _) = parse_llm_commands(content);
+        assert_eq!(cmds, ["sudo pacman -Syu", "systemctl --failed"]);
+        assert_eq!(strip_shell_prompt("#comment"), None);
+        assert!(looks_like_command("/usr/bin/true"));
+        assert!(!looks_like_command("run these commands:"));
+    }
+
+    #[test]
+    fn llm_dedup_ignores_spacing_but_not_case() {
+        let (cmds, _) = parse_llm_commands("pacman -Syu\npacman  -Syu\n  pacman\t-Syu \npacman -syu");
+        assert_eq!(cmds, ["pacman -Syu", "pacman -syu"]);
+    }
+
+    #[test]
+    fn model_flag_beats_config_file() {
+        let config = ExecConfig {
+            model: Some("from-file".to_string()),
+            ..test_config()
+        };
+        let config = ExecConfig {
+            model_override: Some("gpt-4.1".to_string()),
+            ..config
+        };
+        // The flag wins even when OPENAI_MODEL happens to be set in the test environment
+        assert_eq!(llm_model(&config), "gpt-4.1");
+        let body = serde_json::to_string(&openai_request("system", "hi", &config)).unwrap();
+        assert!(body.contains(r#""model":"gpt-4.1""#));
+    }
+
+    #[test]
+    fn sampling_settings_validate_ranges() {
+        assert_eq!(llm_temperature(None, None).unwrap(), 1.0);
+        assert_eq!(llm_temperature(Some("0.2"), Some(1.5)).unwrap(), 0.2);
+        assert_eq!(llm_temperature(None, Some(1.5)).unwrap(), 1.5);
+        assert!(llm_temperature(Some("2.5"), None).is_err());
+        assert!(llm_temperature(Some("hot"), None).is_err());
+        assert_eq!(llm_max_tokens(None, None).unwrap(), 150);
+        assert_eq!(llm_max_tokens(Some("800"), None).unwrap(), 800);
+        assert!(llm_max_tokens(Some("0"), None).is_err());
+        assert!(llm_max_tokens(None, Some(10_000)).is_err());
+    }
+
+    #[test]
+    fn builtin_explanations_cover_common_tools() {
+        let upgrade = explain_builtin("sudo pacman -Syu").unwrap();
+        assert!(upgrade.contains("full system upgrade"));
+        assert!(upgrade.ends_with("Runs as root."));
+        assert!(explain_builtin("pacman -Ss vim").unwrap().contains("changes nothing"));
+        assert_eq!(
+            explain_builtin("systemctl enable --now sshd").unwrap(),
+            "Makes sshd start at boot and applies it immediately."
+        );
+        assert!(explain_builtin("frobnicate --all").is_none());
+    }
+
+    #[test]
+    fn keyring_fix_skips_refresh_offline() {
+        let installed = HashSet::new();
+        let resolver = HttpResolver { config: &test_config() };
+        let online = builtin_translate("fix pacman keyring", &test_config(), &installed, &resolver)
+            .unwrap()
+            .unwrap();
+        let cmds: Vec<_> = online.iter().map(|s| s.cmd.as_str()).collect();
+        assert_eq!(
+            cmds,
+            vec![
+                "sudo pacman -Sy archlinux-keyring",
+                "sudo pacman-key --init",
+                "sudo pacman-key --populate archlinux"
+            ]
+        );
+        assert!(cmds.iter().all(|c| validate(c, &test_config()).is_ok()));
+
+        let offline = ExecConfig {
+            offline: true,
+            ..test_config()
+        };
+        let local = builtin_translate("invalid or corrupted package (PGP signature)", &offline, &installed, &resolver)
+            .unwrap()
+            .unwrap();
+        assert_eq!(local.len(), 2);
+        assert!(local.iter().all(|s| !is_network_pkg_op(&s.cmd)));
+    }
+
+    #[test]
+    fn locked_database_prompt_maps_to_guarded_verb() {
+        let resolver = HttpResolver { config: &test_config() };
+        let cmds = builtin_translate(
+            "error: failed to init transaction (unable to lock database)",
+            &test_config(),
+            &HashSet::new(),
+            &resolver,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmds[0].cmd, "unlock-pacman-db");
+        assert!(validate(&cmds[0].cmd, &test_config()).is_ok());
+        assert!(validate("rm /var/lib/pacman/db.lck", &test_config()).is_err());
+    }
+
+    #[test]
+    fn explicit_config_path_must_exist_and_parse() {
+        let dir = std::env::temp_dir().join(format!("arch-assist-test-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("server.toml");
+        let bad = dir.join("broken.toml");
+        fs::write(&good, "no_sudo = true\nmodel = \"gpt-4o-mini\"\n").unwrap();
+        fs::write(&bad, "no_sudo = maybe\n").unwrap();
+        let loaded = load_config(Some(good.as_path()), None);
+        let broken = load_config(Some(bad.as_path()), None);
+        let missing = load_config(Some(dir.join("missing.toml").as_path()), None);
+        let _ = fs::remove_dir_all(&dir);
+        let loaded = loaded.unwrap();
+        assert!(loaded.no_sudo);
+        assert_eq!(loaded.model.as_deref(), Some("gpt-4o-mini"));
+        assert!(broken.is_err_and(|e| e.to_string().contains("parse config")));
+        assert!(missing.is_err_and(|e| e.to_string().contains("missing.toml")));
+    }
+
+    #[test]
+    fn profiles_merge_over_the_base_config() {
+        let text = "prefer_paru = true\nno_sudo = true\nmodel = \"gpt-4o-mini\"\n\n\
+[profile.server]\nno_sudo = false\nprefer_paru = false\n\n[profile.laptop]\nnotify = true\n";
+        let path = Path::new("config.toml");
+        let base = parse_config(text, None, path).unwrap();
+        assert!(base.prefer_paru && base.no_sudo && !base.notify);
+        let server = parse_config(text, Some("server"), path).unwrap();
+        assert!(!server.prefer_paru && !server.no_sudo);
+        assert_eq!(server.model.as_deref(), Some("gpt-4o-mini"));
+        assert!(parse_config(text, Some("laptop"), path).unwrap().notify);
+        let missing = parse_config(text, Some("work"), path);
+        assert!(missing.is_err_and(|e| e.to_string().contains("defined: laptop, server")));
+        assert!(parse_config("yes = true\n", Some("work"), path).is_err());
+    }
+
+    #[test]
+    fn cached_versions_match_exact_package_name() {
+        let dir = std::env::temp_dir().join(format!("arch-assist-test-pkgcache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "firefox-128.0-1-x86_64.pkg.tar.zst",
+            "firefox-128.0-1-x86_64.pkg.tar.zst.sig",
+            "firefox-developer-edition-129.0b1-1-x86_64.pkg.tar.zst",
+            "firefox-127.0.2-2-x86_64.pkg.tar.xz",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let mut versions: Vec<String> = cached_versions(&dir, "firefox").into_iter().map(|(v, _)| v).collect();
+        versions.sort();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(versions, ["127.0.2-2", "128.0-1"]);
+        let cmd = "sudo pacman -U /var/cache/pacman/pkg/firefox-128.0-1-x86_64.pkg.tar.zst";
+        assert!(validate(cmd, &test_config()).is_ok());
+        assert!(!is_network_pkg_op(cmd));
+    }
+
+    #[test]
+    fn mirror_prompt_is_valid_but_needs_network() {
+        let resolver = HttpResolver { config: &test_config() };
+        let cmds = builtin_translate("fix slow downloads", &test_config(), &HashSet::new(), &resolver)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            cmds[0].cmd,
+            "sudo reflector --latest 20 --sort rate --save /etc/pacman.d/mirrorlist"
+        );
+        assert!(validate(&cmds[0].cmd, &test_config()).is_ok());
+        let offline = ExecConfig {
+            offline: true,
+            ..test_config()
+        };
+        assert!(ensure_offline_ok(&cmds[0], &offline).is_err());
+    }
+
+    #[test]
+    fn unsafe_errors_name_the_rule() {
+        let reason = |cmd: &str| validate(cmd, &test_config()).unwrap_err().to_string();
+        assert_eq!(
+            reason("pacman -Syu && reboot"),
+            "unsafe command blocked: forbidden operator `&&`: pacman -Syu && reboot"
+        );
+        assert!(reason("sudo reboot").contains("program `reboot` not in allowlist"));
+        assert!(reason("sudo dd if=/dev/zero of=/dev/sda").contains("destructive program `dd`"));
+        assert!(reason("rm -rf /").contains("recursive forced `rm`"));
+        let piped = ExecConfig {
+            allow_pipes: true,
+            ..test_config()
+        };
+        let err = validate("journalctl -b | sh", &piped).unwrap_err().to_string();
+        assert!(err.contains("`sh` is not a read-only filter"));
+    }
+
+    #[test]
+    fn aur_helper_setting_cannot_allowlist_arbitrary_programs() {
+        assert_ne!(detect_aur_helper(Some("bash"), &[]).as_deref(), Some("bash"));
+        assert_eq!(detect_aur_helper(Some("bash"), &["bash".to_string()]).as_deref(), Some("bash"));
+        let config = ExecConfig {
+            aur_helper: Some("bash".to_string()),
+            ..test_config()
+        };
+        assert!(validate("bash -c id", &config).is_err());
+    }
+
+    #[test]
+    fn config_lists_extend_allowlist_but_forbidden_wins() {
+        let config = ExecConfig {
+            extra_allowed: vec!["btop".to_string(), "git".to_string()],
+            extra_forbidden: vec!["git".to_string(), "journalctl".to_string()],
+            ..test_config()
+        };
+        assert!(validate("btop --utf-force", &test_config()).is_err());
+        assert!(validate("btop --utf-force", &config).is_ok());
+        assert!(validate("git status", &config).unwrap_err().to_string().contains("extra_forbidden"));
+        assert!(validate("sudo journalctl -b", &config).is_err());
+        assert!(validate("btop && reboot", &config).is_err());
+        let piped = ExecConfig {
+            allow_pipes: true,
+            extra_forbidden: vec!["less".to_string()],
+            ..test_config()
+        };
+        assert!(validate("pacman -Q | less", &piped).is_err());
+    }
+
+    #[test]
+    fn flatpak_ids_and_install_groups() {
+        let listing = "Application ID\norg.videolan.VLC\nio.github.celluloid_player.Celluloid\n";
+        assert_eq!(pick_flatpak_id("vlc", listing).as_deref(), Some("org.videolan.VLC"));
+        assert_eq!(pick_flatpak_id("celluloid", listing).as_deref(), Some("io.github.celluloid_player.Celluloid"));
+        assert_eq!(pick_flatpak_id("gimp", listing), None);
+
+        let config = ExecConfig {
+            prefer_flatpak: true,
+            ..test_config()
+        };
+        // No flatpak binary in tests: an unknown id falls back to pacman
+        let cmds = plan_install(&["definitely-not-on-flathub"], "-S --needed", &config, |_| Ok(PackageOrigin::Flatpak));
+        assert_eq!(cmds.unwrap(), ["sudo pacman -S --needed definitely-not-on-flathub"]);
+        assert!(validate("flatpak install -y flathub org.videolan.VLC", &config).is_ok());
+        assert!(needs_network("flatpak install -y flathub org.videolan.VLC"));
+        assert!(!needs_network("flatpak run org.videolan.VLC"));
+    }
+
+    #[test]
+    fn pipes_need_opt_in_and_read_only_filters() {
+        let piped = ExecConfig {
+            allow_pipes: true,
+            ..test_config()
+        };
+        assert!(validate("journalctl -u sshd | grep error", &test_config()).is_err());
+        assert!(validate("journalctl -u sshd | grep error", &piped).is_ok());
+        assert!(validate("pacman -Q | grep lib | tail -n 5", &piped).is_ok());
+        assert!(validate("journalctl -b | sh", &piped).is_err());
+        assert!(validate("journalctl -b | sudo tee /etc/motd", &piped).is_err());
+        assert!(validate("reboot | grep x", &piped).is_err());
+        assert!(validate("launch firefox | grep x", &piped).is_err());
+        assert!(validate("journalctl -b || grep x", &piped).is_err());
+        assert!(validate("journalctl -b | grep x > out", &piped).is_err());
+        assert!(validate("journalctl -b |", &piped).is_err());
+    }
+
+    #[test]
+    fn pager_applies_only_to_read_only_listings() {
+        for cmd in ["journalctl -u sshd --no-pager -n 50", "systemctl --failed --no-pager", "pacman -Qe", "df -h"] {
+            assert!(is_informational(cmd), "{cmd}");
+        }
+        for cmd in ["sudo pacman -Syu", "sudo systemctl restart bluetooth", "paru -S brave-bin", "kill 42"] {
+            assert!(!is_informational(cmd), "{cmd}");
+        }
+    }
+
+    #[test]
+    fn prompting_pkg_ops_are_told_apart_from_queries() {
+        for cmd in ["sudo pacman -S vlc", "sudo pacman -Syu", "sudo pacman -Rns vlc", "paru -S brave-bin", "paru"] {
+            assert!(is_prompting_pkg_op(cmd), "{cmd}");
+        }
+        for cmd in ["pacman -Qi vlc", "pacman -F libGL.so", "pacman -Ss vlc", "sudo pacman -Sy", "systemctl stop x"] {
+            assert!(!is_prompting_pkg_op(cmd), "{cmd}");
+        }
+        let headless = ExecConfig {
+            non_interactive: true,
+            ..test_config()
+        };
+        assert_eq!(
+            apply_pkg_flags("paru -S brave-bin".into(), &headless),
+            "paru -S brave-bin --noconfirm"
+        );
+    }
+
+    #[test]
+    fn noconfirm_only_reaches_operations_that_prompt() {
+        let config = ExecConfig {
+            yes: true,
+            ..test_config()
+        };
+        for cmd in ["pacman -Qi vlc", "pacman -F libGL.so", "pacman -Ss vlc", "paru -Si brave-bin", "pacman -Qdtq"] {
+            assert_eq!(apply_pkg_flags(cmd.into(), &config), cmd);
+        }
+        for cmd in ["sudo pacman -S vlc", "sudo pacman -Rns vlc", "sudo pacman -U ./x.pkg.tar.zst", "paru -Syu"] {
+            assert_eq!(apply_pkg_flags(cmd.into(), &config), format!("{cmd} --noconfirm"));
+        }
+    }
+
+    #[test]
+    fn extra_pacman_args_go_before_the_targets() {
+        let config = ExecConfig {
+            extra_pacman_args: extra_pacman_args(vec!["--needed".into()], vec!["--color=always".into()]).unwrap(),
+            ..test_config()
+        };
+        assert_eq!(
+            apply_pkg_flags("sudo pacman -S --needed vlc mpv".into(), &config),
+            "sudo pacman -S --needed --color=always vlc mpv"
+        );
+        assert_eq!(apply_pkg_flags("paru -Rns foo".into(), &config), "paru -Rns --needed --color=always foo");
+        assert_eq!(apply_pkg_flags("pacman -Qi vlc".into(), &config), "pacman -Qi vlc");
+        for bad in ["-Rns", "--remove", "--overwrite=*;reboot", "--color always", "vlc", "--"] {
+            assert!(extra_pacman_args(vec![bad.into()], Vec::new()).is_err(), "{bad}");
+        }
+        assert!(extra_pacman_args(vec!["--overwrite='*'".into()], Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn headless_sudo_never_waits_on_a_prompt() {
+        assert_eq!(privilege_flag("sudo", true, false), Some("-A"));
+        assert_eq!(privilege_flag("sudo", true, true), Some("-A"));
+        assert_eq!(privilege_flag("sudo", false, false), Some("-n"));
+        assert_eq!(privilege_flag("doas", true, false), Some("-n"));
+        assert_eq!(privilege_flag("sudo", false, true), None);
+        assert_eq!(privilege_flag("pacman", false, false), None);
+    }
+
+    #[test]
+    fn process_runner_wires_pipeline_segments() {
+        let runner = ProcessRunner {
+            capture: true,
+            json: true,
+            pager: false,
+            sudo_askpass: None,
+        };
+        assert!(runner.run("echo hello pipes | grep pipes").unwrap().success());
+        assert!(!runner.run("echo hello | grep nomatch").unwrap().success());
+    }
+
+    #[test]
+    fn os_release_detects_arch_and_derivatives() {
+        assert!(os_release_is_arch("NAME=\"Arch Linux\"\nID=arch\nBUILD_ID=rolling\n"));
+        assert!(os_release_is_arch("NAME=\"EndeavourOS\"\nID=\"endeavouros\"\nID_LIKE=\"arch\"\n"));
+        assert!(os_release_is_arch("ID=something-new\nID_LIKE=\"arch linux\"\n"));
+        assert!(!os_release_is_arch("NAME=\"Debian GNU/Linux\"\nID=debian\n"));
+        assert!(!os_release_is_arch("ID=ubuntu\nID_LIKE=debian\n"));
+
+        assert!(is_arch_specific("sudo pacman -Syu", &test_config()));
+        assert!(is_arch_specific("paru -S brave-bin", &test_config()));
+        assert!(!is_arch_specific("df -h", &test_config()));
+        assert!(!is_arch_specific("systemctl --failed --no-pager", &test_config()));
+    }
+
+    #[test]
+    fn failed_services_prompt_avoids_pager() {
+        let resolver = HttpResolver { config: &test_config() };
+        let cmds = builtin_translate("what's broken?", &test_config(), &HashSet::new(), &resolver)
+            .unwrap()
+            .unwrap();
+        let cmds: Vec<&str> = cmds.iter().map(|s| s.cmd.as_str()).collect();
+        assert_eq!(cmds, ["systemctl --failed --no-pager", "journalctl -p 3 -xb --no-pager -n 50"]);
+        assert!(cmds.iter().all(|c| validate(c, &test_config()).is_ok()));
+    }
+
+    #[test]
+    fn disk_space_prompt_is_unprivileged_unless_full() {
+        let resolver = HttpResolver { config: &test_config() };
+        let installed = HashSet::new();
+        let check = builtin_translate("how much disk space is left", &test_config(), &installed, &resolver)
+            .unwrap()
+            .unwrap();
+        assert_eq!(check.len(), 2);
+        assert_eq!(check[0].cmd, "df -h");
+        assert!(check.iter().all(|s| !s.cmd.starts_with("sudo") && validate(&s.cmd, &test_config()).is_ok()));
+
+        let full = builtin_translate("my disk full again", &test_config(), &installed, &resolver)
+            .unwrap()
+            .unwrap();
+        assert_eq!(full.len(), 3);
+        assert_eq!(full[2].cmd, "sudo pacman -Sc");
+    }
+
+    #[test]
+    fn kill_prompt_maps_to_guarded_verb() {
+        let resolver = HttpResolver { config: &test_config() };
+        let cmds = builtin_translate("kill firefox", &test_config(), &HashSet::new(), &resolver)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cmds[0].cmd, "kill-process firefox");
+        assert!(validate(&cmds[0].cmd, &test_config()).is_ok());
+        assert!(validate("kill 4242", &test_config()).is_err());
+        assert!(validate("sudo kill 812", &test_config()).is_err());
+        let allowed = ExecConfig {
+            extra_allowed: vec!["kill".to_string()],
+            ..test_config()
+        };
+        assert!(validate("kill 4242 4243", &allowed).is_ok());
+        assert!(validate("kill -s KILL -- 4242", &allowed).is_ok());
+        for cmd in ["kill -9 1", "kill -1", "kill -9 0", "kill 0", "kill -- -812", "kill -TERM -1", "kill"] {
+            assert!(validate(cmd, &allowed).is_err(), "{cmd}");
+        }
+
+        let mut state = test_state(test_config());
+        let err = run("kill-process systemd", &mut state, &MockRunner::default()).unwrap_err();
+        assert!(err.to_string().contains("refusing to kill systemd"));
+        assert!(run("kill-process .*", &mut state, &MockRunner::default()).is_err());
+    }
+
+    #[test]
+    fn builtin_rules_keep_their_output() {
+        // Pinned before the rule table replaced the if/else chain; an empty list means the prompt
+        // falls through to the LLM. "journal bluetooth" is claimed by the earlier bluetooth
+        // rule, as it always was.
+        let config = ExecConfig {
+            reinstall: true,
+            ..test_config()
+        };
+        let installed = HashSet::from(["ufw".to_string()]);
+        let cases: &[(&str, &[&str])] = &[
+            ("enable firewall", &["sudo ufw enable"]),
+            ("turn on the firewall", &["sudo ufw enable"]),
+            ("disable firewall", &[]),
+            ("turn off the firewall", &[]),
+            ("install firewalld", &[]),
+            ("remove firewalld", &["sudo pacman -Rsn firewalld"]),
+            ("logs firewalld", &["journalctl -u firewalld --no-pager -n 50"]),
+            ("open port 22", &["sudo ufw allow 22"]),
+            ("install vlc gimp", &["sudo pacman -S --needed vlc gimp"]),
+            ("install vlc", &[]),
+            ("install networkmanager", &[]),
+            ("remove vlc", &["sudo pacman -Rsn vlc"]),
+            ("uninstall vlc", &["sudo pacman -Rsn vlc"]),
+            ("open firefox", &[]),
+            ("launch firefox", &[]),
+            ("enable sshd", &["sudo systemctl enable sshd"]),
+            ("enable sshd and start", &["sudo systemctl enable --now sshd"]),
+            ("disable cups now", &["sudo systemctl disable --now cups"]),
+            ("enable now", &[]),
+            ("logs sshd", &["journalctl -u sshd --no-pager -n 50"]),
+            ("journal bluetooth", &["sudo systemctl restart bluetooth", "bluetoothctl show"]),
+            ("kill firefox", &["kill-process firefox"]),
+            ("kill foo bar", &[]),
+            ("downgrade vlc", &["echo no older vlc packages in /var/cache/pacman/pkg"]),
+            ("reinstall networkmanager", &["sudo pacman -S networkmanager"]),
+            ("repair gnome-clocks", &["sudo pacman -S gnome-clocks"]),
+            ("reinstall archlinux-keyring", &["sudo pacman -S archlinux-keyring"]),
+            (
+                "hold networkmanager",
+                &[
+                    "sudo pacman -Syu --ignore networkmanager",
+                    "echo to hold permanently, add under [options] in /etc/pacman.conf: IgnorePkg = networkmanager",
+                ],
+            ),
+            (
+                "ignore networkmanager on upgrade",
+                &[
+                    "sudo pacman -Syu --ignore networkmanager",
+                    "echo to hold permanently, add under [options] in /etc/pacman.conf: IgnorePkg = networkmanager",
+                ],
+            ),
+            (
+                "hold gnome-clocks",
+                &[
+                    "sudo pacman -Syu --ignore gnome-clocks",
+                    "echo to hold permanently, add under [options] in /etc/pacman.conf: IgnorePkg = gnome-clocks",
+                ],
+            ),
+            ("repair the sound", &["systemctl --user restart pipewire wireplumber", "pactl info"]),
+            ("repair my wifi", &[]),
+            (
+                "repair my network",
+                &["sudo systemctl restart NetworkManager", "nmcli networking on", "nmcli -t -f DEVICE,STATE d"],
+            ),
+            ("repair sound", &["systemctl --user restart pipewire wireplumber", "pactl info"]),
+            (
+                "repair keyring",
+                &[
+                    "sudo pacman -Sy archlinux-keyring",
+                    "sudo pacman-key --init",
+                    "sudo pacman-key --populate archlinux",
+                ],
+            ),
+            ("upgrade system", &["sudo pacman -Syu"]),
+            ("update system", &["sudo pacman -Syu"]),
+            ("upgrade", &["sudo pacman -Syu"]),
+            ("check updates", &["checkupdates"]),
+            ("pending updates", &["checkupdates"]),
+            ("list installed", &["pacman -Q"]),
+            ("list installed explicitly containing python", &["list-installed -Qe python"]),
+            ("list installed from the aur", &["pacman -Qm"]),
+            ("remove orphans", &["remove-orphans"]),
+            ("clean cache", &["sudo pacman -Sc"]),
+            ("cleanup", &["sudo pacman -Sc"]),
+            ("disk space", &["df -h", "du -sh /var/cache/pacman/pkg /var/log/journal"]),
+            ("disk full", &["df -h", "du -sh /var/cache/pacman/pkg /var/log/journal", "sudo pacman -Sc"]),
+            ("what's broken", &["systemctl --failed --no-pager", "journalctl -p 3 -xb --no-pager -n 50"]),
+            ("failed services", &["systemctl --failed --no-pager", "journalctl -p 3 -xb --no-pager -n 50"]),
+            ("update mirrors", &["sudo reflector --latest 20 --sort rate --save /etc/pacman.d/mirrorlist"]),
+            ("slow downloads", &["sudo reflector --latest 20 --sort rate --save /etc/pacman.d/mirrorlist"]),
+            ("rebuild initramfs", &["sudo mkinitcpio -P"]),
+            ("mkinitcpio", &["sudo mkinitcpio -P"]),
+            (
+                "fix keyring",
+                &[
+                    "sudo pacman -Sy archlinux-keyring",
+                    "sudo pacman-key --init",
+                    "sudo pacman-key --populate archlinux",
+                ],
+            ),
+            (
+                "invalid or corrupted package",
+                &[
+                    "sudo pacman -Sy archlinux-keyring",
+                    "sudo pacman-key --init",
+                    "sudo pacman-key --populate archlinux",
+                ],
+            ),
+            ("unable to lock database", &["unlock-pacman-db"]),
+            ("db.lck", &["unlock-pacman-db"]),
+            ("fix sound", &["systemctl --user restart pipewire wireplumber", "pactl info"]),
+            ("fix audio", &["systemctl --user restart pipewire wireplumber", "pactl info"]),
+            (
+                "fix network",
+                &["sudo systemctl restart NetworkManager", "nmcli networking on", "nmcli -t -f DEVICE,STATE d"],
+            ),
+            (
+                "fix internet",
+                &["sudo systemctl restart NetworkManager", "nmcli networking on", "nmcli -t -f DEVICE,STATE d"],
+            ),
+            (
+                "network status",
+                &["sudo systemctl restart NetworkManager", "nmcli networking on", "nmcli -t -f DEVICE,STATE d"],
+            ),
+            ("wifi status", &["nmcli general status", "nmcli -t -f DEVICE,STATE d"]),
+            ("fix bluetooth", &["sudo systemctl restart bluetooth", "bluetoothctl show"]),
+            ("fix time", &["sudo timedatectl set-ntp true", "timedatectl status"]),
+            ("time sync", &["sudo timedatectl set-ntp true", "timedatectl status"]),
+            ("clock", &["sudo timedatectl set-ntp true", "timedatectl status"]),
+            ("set timezone Europe/London", &["sudo timedatectl set-timezone Europe/London", "timedatectl status"]),
+            ("write a poem", &[]),
+        ];
+        for (prompt, expected) in cases {
+            let cmds: Vec<String> = builtin_translate(prompt, &config, &installed, &ExampleResolver)
+                .unwrap()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|s| s.cmd)
+                .collect();
+            assert_eq!(cmds, *expected, "{prompt}");
+        }
+    }
+
+    #[test]
+    fn mount_takes_only_disk_and_partition_names() {
+        let resolver = HttpResolver { config: &test_config() };
+        let installed = HashSet::new();
+        let translate = |prompt: &str| builtin_translate(prompt, &test_config(), &installed, &resolver);
+        assert_eq!(translate("mount sdb1").unwrap().unwrap()[0].cmd, "udisksctl mount -b /dev/sdb1");
+        assert_eq!(translate("list drives").unwrap().unwrap()[0].cmd, "lsblk -f");
+        assert!(translate("mount ../etc").is_err());
+        assert!(translate("mount /dev/null").is_err());
+        for dev in ["/dev/sda", "/dev/vdb2", "/dev/nvme0n1", "/dev/nvme1n1p3", "/dev/mmcblk0p1"] {
+            assert!(is_block_device(dev), "{dev}");
+        }
+        for dev in ["/dev/sd", "/dev/sda1b", "/dev/nvme0", "/dev/nvme0n1p", "/dev/mmcblk0x1", "sda1"] {
+            assert!(!is_block_device(dev), "{dev}");
+        }
+        assert!(validate("udisksctl mount -b /dev/sdb1", &test_config()).is_ok());
+        assert!(validate("udisksctl mount -b /dev/mapper/root", &test_config()).is_err());
+    }
+
+    #[test]
+    fn hostname_is_checked_before_the_command_is_built() {
+        let resolver = HttpResolver { config: &test_config() };
+        let translate = |prompt: &str| builtin_translate(prompt, &test_config(), &HashSet::new(), &resolver);
+        let cmds = translate("set hostname archbox").unwrap().unwrap();
+        assert_eq!(cmds[0].cmd, "sudo hostnamectl set-hostname archbox");
+        assert_eq!(cmds[1].cmd, "hostnamectl status");
+        for bad in ["-box", "box-", "arch_box", "a.b", "x".repeat(64).as_str(), ""] {
+            assert!(!is_hostname(bad), "{bad}");
+            assert!(translate(&format!("set hostname {bad}")).is_err(), "{bad}");
+        }
+        assert!(is_hostname("my-laptop-2"));
+        assert!(is_hostname(&"x".repeat(63)));
+    }
+
+    #[test]
+    fn locale_and_keymap_reject_gibberish() {
+        let resolver = HttpResolver { config: &test_config() };
+        let translate = |prompt: &str| builtin_translate(prompt, &test_config(), &HashSet::new(), &resolver);
+        let cmds = translate("set locale en_US.UTF-8").unwrap().unwrap();
+        assert_eq!(cmds[0].cmd, "sudo localectl set-locale LANG=en_US.UTF-8");
+        assert_eq!(translate("set keymap uk").unwrap().unwrap()[0].cmd, "sudo localectl set-keymap uk");
+        for good in ["en_US.UTF-8", "ast_ES.UTF-8", "sr_RS.UTF-8@latin", "de_DE.ISO-8859-1"] {
+            assert!(is_locale_name(good), "{good}");
+        }
+        for bad in ["english", "en_US", "en_us.UTF-8", "EN_US.UTF-8", "en_US.", "en_US.UTF-8@", "en_US.UTF 8"] {
+            assert!(!is_locale_name(bad), "{bad}");
+        }
+        assert!(translate("set locale klingon").is_err());
+        assert!(translate("set keymap ../../etc").is_err());
+    }
+
+    #[test]
+    fn battery_is_picked_and_summarized_from_upower() {
+        let listing = "/org/freedesktop/UPower/devices/line_power_AC\n\
+/org/freedesktop/UPower/devices/battery_BAT0\n\
+/org/freedesktop/UPower/devices/mouse_hidpp_battery_0\n\
+/org/freedesktop/UPower/devices/DisplayDevice\n";
+        assert_eq!(battery_devices(listing), ["/org/freedesktop/UPower/devices/battery_BAT0"]);
+        let info = "  native-path:          BAT0\n  battery\n    present:             yes\n\
+    state:               discharging\n    time to empty:       3.2 hours\n    percentage:          87%\n";
+        assert_eq!(battery_summary("BAT0", info), "BAT0: 87% (discharging, 3.2 hours to empty)");
+        assert_eq!(battery_summary("BAT1", ""), "BAT1: ?% (unknown state)");
+    }
+
+    #[test]
+    fn ssh_key_comment_is_user_at_host() {
+        assert_eq!(ssh_key_comment(Some("alice"), Some("archbox\n")), "alice@archbox");
+        assert_eq!(ssh_key_comment(None, None), "user@localhost");
+        assert_eq!(ssh_key_comment(Some("bob\"; x"), Some("")), "bobx@localhost");
+        let resolver = HttpResolver { config: &test_config() };
+        let cmds = builtin_translate("generate ssh key", &test_config(), &HashSet::new(), &resolver).unwrap().unwrap();
+        assert!(cmds[0].cmd.starts_with("ssh-keygen -t ed25519 -C \""), "{}", cmds[0].cmd);
+        assert!(validate(&cmds[0].cmd, &test_config()).is_ok());
+    }
+
+    #[test]
+    fn every_rule_example_reaches_its_own_rule() {
+        let config = ExecConfig {
+            offline: true,
+            reinstall: true,
+            ..test_config()
+        };
+        let installed = HashSet::new();
+        for (at, rule) in BUILTIN_RULES.iter().enumerate() {
+            for example in rule.examples {
+                let ctx = PromptCtx::new(example, &config, &installed, &ExampleResolver);
+                let claimed = BUILTIN_RULES.iter().position(|r| (r.matches)(&ctx));
+                assert_eq!(claimed, Some(at), "'{example}' is claimed by another rule");
+                let cmds = builtin_translate(example, &config, &installed, &ExampleResolver).unwrap();
+                assert!(cmds.is_some_and(|c| !c.is_empty()), "{example}");
+            }
+        }
+    }
+
+    #[test]
+    fn translate_subcommand_stays_builtin() {
+        let state = test_state(test_config());
+        assert!(handle_translate("write me a poem about pacman", &state).unwrap().is_none());
+        let commands = handle_translate("check updates", &state).unwrap().unwrap();
+        assert_eq!(commands[0].cmd, "checkupdates");
+    }
+
+    #[test]
+    fn snapshot_goes_before_first_package_change() {
+        let config = ExecConfig {
+            snapshot: true,
+            ..test_config()
+        };
+        let sugg = |cmd: &str| Suggestion {
+            cmd: cmd.to_string(),
+            reason: "test".into(),
+        };
+        let batch = vec![sugg("checkupdates"), sugg("sudo pacman -Syu"), sugg("sudo pacman -S --needed vlc")];
+        let cmds: Vec<String> = with_snapshot(batch.clone(), &config, true).into_iter().map(|s| s.cmd).collect();
+        assert_eq!(
+            cmds,
+            [
+                "checkupdates",
+                "sudo snapper create --description 'arch-assist pre-op'",
+                "sudo pacman -Syu",
+                "sudo pacman -S --needed vlc"
+            ]
+        );
+        assert!(validate(&cmds[1], &config).is_ok());
+        assert_eq!(with_snapshot(batch, &config, false).len(), 3);
+        assert_eq!(with_snapshot(vec![sugg("pacman -Ss vlc")], &config, true).len(), 1);
+        assert!(changes_packages("sudo pacman -U /var/cache/pacman/pkg/vlc-3.0.20-1-x86_64.pkg.tar.zst"));
+    }
+
+    #[test]
+    fn max_commands_truncates_builtin_batches() {
+        let config = ExecConfig {
+            max_commands: Some(1),
+            ..test_config()
+        };
+        let (commands, source) =
+            suggest("fix sound", &config, &HashSet::new(), &HttpResolver { config: &config }).unwrap();
+        assert_eq!(source, "builtin");
+        assert_eq!(commands.len(), 1);
+        assert_eq!(cap_commands(commands.clone(), &test_config()).len(), 1);
+    }
+
+    #[test]
+    fn relaxed_prompts_reach_builtins_and_list_intents() {
+        assert_eq!(relax_prompt("Can you please kill firefox?"), "kill firefox");
+        assert_eq!(relax_prompt("how do I upgrade system please"), "upgrade system");
+        assert_eq!(relax_prompt("install vlc"), "install vlc");
+        let resolver = HttpResolver { config: &test_config() };
+        let relaxed = relax_prompt("could you kill firefox");
+        let cmds = builtin_translate(&relaxed, &test_config(), &HashSet::new(), &resolver).unwrap().unwrap();
+        assert_eq!(cmds[0].cmd, "kill-process firefox");
+        let message = no_llm_message("OPENAI_API_KEY not set");
+        assert!(message.starts_with("OPENAI_API_KEY not set\n"));
+        assert!(message.contains("\n  upgrade system\n"));
+    }
+
+    #[test]
+    fn firewall_prompts_follow_installed_backend() {
+        let config = ExecConfig {
+            reinstall: true,
+            ..test_config()
+        };
+        let resolver = StaticResolver(HashMap::from([("ufw", PackageOrigin::Repo)]));
+        let cmds = |prompt: &str, installed: &[&str]| -> Vec<String> {
+            let installed = installed.iter().map(|p| p.to_string()).collect();
+            builtin_translate(prompt, &config, &installed, &resolver)
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .map(|s| s.cmd)
+                .collect()
+        };
+        assert_eq!(cmds("enable firewall", &["ufw"]), vec!["sudo ufw enable"]);
+        assert_eq!(cmds("open port 22", &["ufw"]), vec!["sudo ufw allow 22"]);
+        assert_eq!(
+            cmds("open port 22", &["firewalld"]),
+            vec!["sudo firewall-cmd --add-port=22/tcp --permanent", "sudo firewall-cmd --reload"]
+        );
+        assert_eq!(
+            cmds("enable firewall", &[]),
+            vec!["sudo pacman -S --needed ufw", "sudo ufw enable"]
+        );
+        for cmd in cmds("enable the firewall and open port 443", &["firewalld"]) {
+            assert!(validate(&cmd, &config).is_ok(), "{cmd}");
+        }
+        assert!(builtin_translate("open port ssh", &config, &HashSet::new(), &resolver).is_err());
+    }
+
+    #[test]
+    fn set_timezone_checks_zone_format() {
+        let resolver = HttpResolver { config: &test_config() };
+        let installed = HashSet::new();
+        let cmds = |prompt: &str| -> Vec<String> {
+            builtin_translate(prompt, &test_config(), &installed, &resolver)
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .map(|s| s.cmd)
+                .collect()
+        };
+        assert_eq!(
+            cmds("set timezone Europe/London"),
+            vec!["sudo timedatectl set-timezone Europe/London", "timedatectl status"]
+        );
+        assert!(cmds("set timezone America/Argentina/Buenos_Aires")[0].ends_with(" America/Argentina/Buenos_Aires"));
+        let bad = builtin_translate("set timezone london", &test_config(), &installed, &resolver);
+        assert!(bad.is_err_and(|e| e.to_string().contains("Europe/London")));
+        assert!(!is_timezone_name("Europe/"));
+        assert!(is_timezone_name("Etc/GMT+5"));
+    }
+
+    #[test]
+    fn list_installed_prompts_pick_query_and_filter() {
+        let resolver = HttpResolver { config: &test_config() };
+        let installed = HashSet::new();
+        let cmd = |prompt: &str| {
+            builtin_translate(prompt, &test_config(), &installed, &resolver).unwrap().unwrap()[0]
+                .cmd
+                .clone()
+        };
+        assert_eq!(cmd("list installed"), "pacman -Q");
+        assert_eq!(cmd("list installed explicitly"), "pacman -Qe");
+        assert_eq!(cmd("what's installed from the AUR"), "pacman -Qm");
+        assert_eq!(cmd("list installed containing python"), "list-installed -Q python");
+        assert!(validate("list-installed -Q python", &test_config()).is_ok());
+        assert_eq!(
+            filter_lines("python 3.12.4-1\npython-pip 24.0-1\nvim 9.1-1\n", "PYTHON"),
+            ["python 3.12.4-1", "python-pip 24.0-1"]
+        );
+    }
+
+    #[test]
+    fn initramfs_prompt_works_offline() {
+        let offline = ExecConfig {
+            offline: true,
+            ..test_config()
+        };
+        let resolver = HttpResolver { config: &offline };
+        let cmds = builtin_translate("regenerate initramfs", &offline, &HashSet::new(), &resolver)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmds[0].cmd, "sudo mkinitcpio -P");
+        assert!(validate(&cmds[0].cmd, &offline).is_ok());
+        assert!(ensure_offline_ok(&cmds[0], &offline).is_ok());
+    }
+}
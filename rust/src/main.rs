@@ -1,45 +1,227 @@
-use std::io::{self, Write};
-use std::process::{Command, Stdio};
-
-use clap::{Parser, Subcommand};
-use reqwest::blocking::Client as HttpClient;
-use serde::{Deserialize, Serialize};
-use shell_words::split as shell_split;
-use thiserror::Error;
-use std::collections::HashSet;
-use std::path::{Path, PathBuf};
 use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use arch_assist::{
+    color_allowed, extra_pacman_args, handle_doctor, handle_explain, handle_history, handle_info, handle_intents,
+    handle_prompt, handle_prompt_script, handle_search, handle_translate, llm_max_tokens, llm_temperature, load_config,
+    load_installed, on_arch_based_system, run, validate, AppState, AssistError, Backend, ExecConfig, PackageOrigin,
+    Paint, ProcessRunner,
+};
+use clap::{CommandFactory, Parser, Subcommand};
 
 #[derive(Parser)]
-#[command(name = "arch-assist", version, about = "Lightweight Arch helper with AI-ish shortcuts")]
+#[command(
+    name = "arch-assist",
+    version,
+    about = "Lightweight Arch helper with AI-ish shortcuts",
+    after_help = "Exit codes: 0 success, 1 command failed, 2 unsafe command blocked, 3 network or LLM error; \
+when a command exits nonzero, its own code (the first failing one in a batch)"
+)]
 struct Cli {
     /// Only print the commands that would run
-    #[arg(long, global = true)]
+    #[arg(long, global = true, overrides_with = "no_dry_run")]
     dry_run: bool,
+    /// Undo `dry_run = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "dry_run", help_heading = "Config overrides")]
+    no_dry_run: bool,
 
     /// Auto-run AI suggestions instead of only printing them
-    #[arg(long, global = true)]
+    #[arg(long, global = true, overrides_with = "no_auto")]
     auto: bool,
+    /// Undo `auto = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "auto", help_heading = "Config overrides")]
+    no_auto: bool,
 
     /// Require offline-safe commands (block pacman/paru downloads)
-    #[arg(long, global = true)]
+    #[arg(long, global = true, overrides_with = "no_offline")]
     offline: bool,
+    /// Undo `offline = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "offline", help_heading = "Config overrides")]
+    no_offline: bool,
 
-    /// Append --noconfirm to pacman/paru actions
-    #[arg(long, global = true)]
+    /// Skip confirmation and append --noconfirm to pacman/paru installs, removals and upgrades
+    #[arg(long, global = true, overrides_with = "no_yes")]
     yes: bool,
+    /// Undo `yes = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "yes", help_heading = "Config overrides")]
+    no_yes: bool,
 
     /// Prefer paru for installs even when a -bin package is not specified
-    #[arg(long, global = true)]
+    #[arg(long, global = true, overrides_with = "no_prefer_paru")]
     prefer_paru: bool,
+    /// Undo `prefer_paru = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "prefer_paru", help_heading = "Config overrides")]
+    no_prefer_paru: bool,
+
+    /// Install apps from Flathub when `flatpak search` finds them
+    #[arg(long, global = true, overrides_with = "no_prefer_flatpak")]
+    prefer_flatpak: bool,
+    /// Undo `prefer_flatpak = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "prefer_flatpak", help_heading = "Config overrides")]
+    no_prefer_flatpak: bool,
 
     /// Avoid sudo when using pacman
-    #[arg(long, global = true)]
+    #[arg(long, global = true, overrides_with = "sudo")]
     no_sudo: bool,
+    /// Undo `no_sudo = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "no_sudo", help_heading = "Config overrides")]
+    sudo: bool,
+
+    /// Use doas instead of sudo for privileged commands
+    #[arg(long, global = true, overrides_with = "no_use_doas")]
+    use_doas: bool,
+    /// Undo `use_doas = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "use_doas", help_heading = "Config overrides")]
+    no_use_doas: bool,
+
+    /// Buffer command output and print it back with separators
+    #[arg(long, global = true, overrides_with = "no_capture")]
+    capture: bool,
+    /// Undo `capture = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "capture", help_heading = "Config overrides")]
+    no_capture: bool,
+
+    /// Print only the commands themselves, without reasons or source comments
+    #[arg(long, global = true, overrides_with = "no_quiet")]
+    quiet: bool,
+    /// Undo `quiet = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "quiet", help_heading = "Config overrides")]
+    no_quiet: bool,
 
     /// Log exit codes and command outcomes
-    #[arg(long, global = true)]
+    #[arg(long, global = true, overrides_with = "no_verbose")]
     verbose: bool,
+    /// Undo `verbose = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "verbose", help_heading = "Config overrides")]
+    no_verbose: bool,
+
+    /// Install packages even when pacman reports them as already installed
+    #[arg(long, global = true)]
+    reinstall: bool,
+
+    /// Refuse package operations when /etc/os-release is not Arch or a derivative
+    #[arg(long, global = true, overrides_with = "no_strict_distro")]
+    strict_distro: bool,
+    /// Undo `strict_distro = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "strict_distro", help_heading = "Config overrides")]
+    no_strict_distro: bool,
+
+    /// Print each command's reason and a one-line explanation right before it runs
+    #[arg(long, global = true, overrides_with = "no_explain_before_run")]
+    explain_before_run: bool,
+    /// Undo `explain_before_run = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "explain_before_run", help_heading = "Config overrides")]
+    no_explain_before_run: bool,
+
+    /// Allow `|` into read-only filters (grep, tail, head, less); each segment is validated
+    #[arg(long, global = true, overrides_with = "no_allow_pipes")]
+    allow_pipes: bool,
+    /// Undo `allow_pipes = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "allow_pipes", help_heading = "Config overrides")]
+    no_allow_pipes: bool,
+
+    /// Send a desktop notification (notify-send) when an --auto batch finishes
+    #[arg(long, global = true, overrides_with = "no_notify")]
+    notify: bool,
+    /// Undo `notify = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "notify", help_heading = "Config overrides")]
+    no_notify: bool,
+
+    /// Print suggestions as a JSON array instead of annotated lines
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Confirm each suggestion separately: [y]es / [n]o / [a]ll / [q]uit
+    #[arg(long, global = true, overrides_with = "no_interactive")]
+    interactive: bool,
+    /// Undo `interactive = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "interactive", help_heading = "Config overrides")]
+    no_interactive: bool,
+
+    /// Disable colored output (also honored: NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Do not append executed commands to the history log
+    #[arg(long, global = true, overrides_with = "history")]
+    no_history: bool,
+    /// Undo `no_history = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "no_history", help_heading = "Config overrides")]
+    history: bool,
+
+    /// Do not print close package names when a package cannot be resolved
+    #[arg(long, global = true, overrides_with = "suggestions")]
+    no_suggestions: bool,
+    /// Undo `no_suggestions = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "no_suggestions", help_heading = "Config overrides")]
+    suggestions: bool,
+
+    /// Ignore cached LLM responses and do not store new ones
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Force --noconfirm on every pacman/AUR-helper operation (for CI and other runs without a terminal)
+    #[arg(long, global = true, overrides_with = "no_non_interactive")]
+    non_interactive: bool,
+    /// Undo `non_interactive = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "non_interactive", help_heading = "Config overrides")]
+    no_non_interactive: bool,
+
+    /// Show long read-only output (logs, listings, status) through $PAGER, default `less -R`
+    #[arg(long, global = true, overrides_with = "no_pager")]
+    pager: bool,
+    /// Undo `pager = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "pager", help_heading = "Config overrides")]
+    no_pager: bool,
+
+    /// Treat every package as an AUR package, skipping the repo/AUR lookups
+    #[arg(long, global = true, conflicts_with = "assume_repo")]
+    assume_aur: bool,
+
+    /// Treat every package as a repo package, skipping the repo/AUR lookups
+    #[arg(long, global = true)]
+    assume_repo: bool,
+
+    /// Extra long option for pacman/AUR-helper installs, removals and upgrades (repeatable)
+    #[arg(long = "pacman-arg", global = true, value_name = "ARG", allow_hyphen_values = true)]
+    pacman_args: Vec<String>,
+
+    /// Program sudo runs (`sudo -A`, SUDO_ASKPASS) to get a password when no terminal is attached
+    #[arg(long, global = true, value_name = "PROGRAM")]
+    sudo_askpass: Option<String>,
+
+    /// Take a snapper snapshot before any install or upgrade (skipped with a warning without snapper)
+    #[arg(long, global = true, overrides_with = "no_snapshot")]
+    snapshot: bool,
+    /// Undo `snapshot = true` from config.toml for this run
+    #[arg(long, global = true, overrides_with = "snapshot", help_heading = "Config overrides")]
+    no_snapshot: bool,
+
+    /// Never suggest or run more than N commands per prompt (the rest are reported on stderr)
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_commands: Option<u32>,
+
+    /// LLM model to ask (beats OPENAI_MODEL/OLLAMA_MODEL and the config file)
+    #[arg(long, global = true, value_name = "NAME")]
+    model: Option<String>,
+
+    /// Seconds to wait on OpenAI and package-registry HTTP calls
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 10)]
+    timeout: u64,
+
+    /// Extra attempts for HTTP calls that hit 429/5xx or a connection error
+    #[arg(long, global = true, value_name = "N", default_value_t = 2)]
+    retries: u32,
+
+    /// Read defaults from this config file instead of ~/.config/arch-assist/config.toml
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Apply the [profile.NAME] section of the config file over its base settings
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
 
     /// Path to the installed-packages list
     #[arg(long, global = true, value_name = "FILE", default_value = "installed_packages.txt")]
@@ -55,951 +237,181 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Interpret a natural language prompt into real commands
+    /// Interpret a natural language prompt into real commands ("-" reads one prompt per line from stdin)
     Ai { prompt: String },
     /// Run a single command after safety validation
     Run { command: String },
+    /// List previously executed commands, or replay one
+    History {
+        /// Re-run entry N (as numbered in the listing)
+        #[arg(long, value_name = "N", conflicts_with = "last")]
+        replay: Option<usize>,
+        /// Re-run the most recent entry
+        #[arg(long)]
+        last: bool,
+    },
+    /// Search the official repos and the AUR
+    Search {
+        query: String,
+        /// Only show AUR results
+        #[arg(long, conflicts_with = "repo_only")]
+        aur_only: bool,
+        /// Only show official repo results
+        #[arg(long)]
+        repo_only: bool,
+    },
+    /// List the builtin intents, their trigger phrases and what they expand to
+    Intents,
+    /// Print what the builtin rules make of a prompt; never calls the LLM
+    Translate { prompt: String },
+    /// Describe what a command does without running it
+    Explain { command: String },
+    /// Show details for one package from the repos or the AUR
+    Info { pkg: String },
+    /// Check the environment: distro, package tools, API key, network, multilib
+    Doctor,
+    /// Print a shell completion script to stdout
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let color = color_allowed(cli.no_color, cli.json);
+    if let Err(e) = try_main(cli, color) {
+        eprintln!("{}", Paint::Red.apply(&format!("error: {e}"), color, true));
+        std::process::exit(e.exit_code());
+    }
 }
 
-#[derive(Debug, Error)]
-enum AssistError {
-    #[error("unsafe command blocked: {0}")]
-    Unsafe(String),
-    #[error("command failed: {0}")]
-    CommandFailed(String),
-}
-
-fn main() -> Result<(), AssistError> {
-    let cli = Cli::parse();
+fn try_main(cli: Cli, color: bool) -> Result<(), AssistError> {
+    // Completions need neither config nor state; a broken config file should not block them
+    if let Commands::Completions { shell } = cli.command {
+        let mut cmd = Cli::command();
+        let name = cmd.get_name().to_string();
+        clap_complete::generate(shell, &mut cmd, name, &mut io::stdout());
+        return Ok(());
+    }
+    let file = load_config(cli.config.as_deref(), cli.profile.as_deref())?;
     let mut state = AppState {
         config: ExecConfig {
-        dry_run: cli.dry_run,
-        auto: cli.auto,
-        offline: cli.offline,
-        yes: cli.yes,
-        prefer_paru: cli.prefer_paru,
-        no_sudo: cli.no_sudo,
-        verbose: cli.verbose,
+            dry_run: flag(cli.dry_run, cli.no_dry_run, file.dry_run),
+            auto: flag(cli.auto, cli.no_auto, file.auto),
+            offline: flag(cli.offline, cli.no_offline, file.offline),
+            yes: flag(cli.yes, cli.no_yes, file.yes),
+            prefer_paru: flag(cli.prefer_paru, cli.no_prefer_paru, file.prefer_paru),
+            no_sudo: flag(cli.no_sudo, cli.sudo, file.no_sudo),
+            use_doas: flag(cli.use_doas, cli.no_use_doas, file.use_doas),
+            capture: flag(cli.capture, cli.no_capture, file.capture),
+            quiet: flag(cli.quiet, cli.no_quiet, file.quiet),
+            verbose: flag(cli.verbose, cli.no_verbose, file.verbose),
+            json: cli.json,
+            color,
+            interactive: flag(cli.interactive, cli.no_interactive, file.interactive),
+            history: !flag(cli.no_history, cli.history, file.no_history),
+            suggestions: !flag(cli.no_suggestions, cli.suggestions, file.no_suggestions),
+            reinstall: cli.reinstall,
+            strict_distro: flag(cli.strict_distro, cli.no_strict_distro, file.strict_distro),
+            explain_before_run: flag(cli.explain_before_run, cli.no_explain_before_run, file.explain_before_run),
+            allow_pipes: flag(cli.allow_pipes, cli.no_allow_pipes, file.allow_pipes),
+            prefer_flatpak: flag(cli.prefer_flatpak, cli.no_prefer_flatpak, file.prefer_flatpak),
+            notify: flag(cli.notify, cli.no_notify, file.notify),
+            snapshot: flag(cli.snapshot, cli.no_snapshot, file.snapshot),
+            pager: flag(cli.pager, cli.no_pager, file.pager),
+            non_interactive: flag(cli.non_interactive, cli.no_non_interactive, file.non_interactive),
+            assume_origin: match (cli.assume_aur, cli.assume_repo) {
+                (true, _) => Some(PackageOrigin::Aur),
+                (_, true) => Some(PackageOrigin::Repo),
+                _ => None,
+            },
+            max_commands: cli.max_commands.map(|n| n as usize),
+            timeout: cli.timeout,
+            retries: cli.retries,
+            package_cache_ttl: Duration::from_secs(file.package_cache_ttl_hours.unwrap_or(24) * 3600),
+            llm_cache_ttl: if cli.no_cache {
+                Duration::ZERO
+            } else {
+                Duration::from_secs(file.llm_cache_ttl_hours.unwrap_or(24) * 3600)
+            },
+            aur_helper: std::env::var("AUR_HELPER").ok().or(file.aur_helper),
+            extra_allowed: file.extra_allowed,
+            extra_forbidden: file.extra_forbidden,
+            extra_aur_packages: file.extra_aur_packages,
+            extra_pacman_args: extra_pacman_args(file.extra_pacman_args, cli.pacman_args)?,
+            sudo_askpass: cli.sudo_askpass.or(file.sudo_askpass).filter(|p| !p.trim().is_empty()),
+            model_override: cli.model.filter(|m| !m.trim().is_empty()),
+            model: file.model,
+            system_prompt: std::env::var("ARCH_ASSIST_SYSTEM_PROMPT")
+                .ok()
+                .filter(|p| !p.trim().is_empty())
+                .or(file.system_prompt),
+            temperature: llm_temperature(std::env::var("OPENAI_TEMPERATURE").ok().as_deref(), file.temperature)?,
+            max_tokens: llm_max_tokens(std::env::var("OPENAI_MAX_TOKENS").ok().as_deref(), file.max_tokens)?,
+            backend: Backend::parse(
+                &std::env::var("ARCH_ASSIST_BACKEND")
+                    .ok()
+                    .or(file.backend)
+                    .unwrap_or_else(|| "openai".to_string()),
+            )?,
         },
         installed_file: cli.installed_file.clone(),
         installed: load_installed(&cli.installed_file),
+        prompt: None,
     };
 
-    if cli.clear_installed {
-        fs::write(&cli.installed_file, "").map_err(|e| AssistError::CommandFailed(format!("clear installed ({e})")))?;
-        println!("Cleared installed list at {}", cli.installed_file.display());
-        return Ok(());
-    }
-
-    match cli.command {
-        Commands::Ai { prompt } => handle_prompt(&prompt, &mut state)?,
-        Commands::Run { command } => {
-            validate(&command)?;
-            run(&command, &mut state)?;
-        }
-    }
-
-    Ok(())
-}
-
-struct ExecConfig {
-    dry_run: bool,
-    auto: bool,
-    offline: bool,
-    yes: bool,
-    prefer_paru: bool,
-    no_sudo: bool,
-    verbose: bool,
-}
-
-struct AppState {
-    config: ExecConfig,
-    installed_file: PathBuf,
-    installed: HashSet<String>,
-}
-
-fn handle_prompt(prompt: &str, state: &mut AppState) -> Result<(), AssistError> {
-    if let Some(commands) = builtin_translate(prompt, state) {
-        for sugg in &commands {
-            println!("{}    # {}", sugg.cmd, sugg.reason);
-        }
-
-        if !state.config.auto {
-            // Suggest but do not run unless explicitly requested
-            return Ok(());
-        }
-
-        if !confirm(&commands, &state.config)? {
-            return Ok(());
-        }
-
-        for sugg in commands {
-            ensure_offline_ok(&sugg, &state.config)?;
-            validate(&sugg.cmd)?;
-            run(&sugg.cmd, state)?;
-        }
-        return Ok(());
-    }
-
-    // Fall back to OpenAI suggestion
-    let llm_cmds = llm_translate(prompt, state)?;
-    for cmd in &llm_cmds {
-        println!("{cmd}    # from openai");
-    }
-
-    if !state.config.auto {
-        return Ok(());
-    }
-
-    if !confirm(
-        &llm_cmds
-            .iter()
-            .map(|c| Suggestion {
-                cmd: c.clone(),
-                reason: "LLM suggestion",
-            })
-            .collect::<Vec<_>>(),
-        &state.config,
-    )? {
-        return Ok(());
-    }
-
-    for cmd in llm_cmds {
-        let sugg = Suggestion {
-            cmd: cmd.clone(),
-            reason: "LLM suggestion",
-        };
-        ensure_offline_ok(&sugg, &state.config)?;
-        validate(&sugg.cmd)?;
-        run(&sugg.cmd, state)?;
-    }
-
-    Ok(())
-}
-
-fn installer_for(pkg: &str, config: &ExecConfig) -> &'static str {
-    if config.prefer_paru || pkg.ends_with("-bin") {
-        "paru"
-    } else if config.no_sudo {
-        "pacman"
-    } else {
-        "sudo pacman"
-    }
-}
-
-#[derive(Debug, Clone)]
-struct Suggestion {
-    cmd: String,
-    reason: &'static str,
-}
-
-fn builtin_translate(prompt: &str, state: &AppState) -> Option<Vec<Suggestion>> {
-    let lower = prompt.to_lowercase();
-    let mut tokens = lower.split_whitespace();
-    let first = tokens.next().unwrap_or("");
-    let rest = tokens.collect::<Vec<_>>().join(" ").trim().to_string();
-
-    if lower == "test ai" {
-        return Some(vec![Suggestion {
-            cmd: "echo ai-ok".to_string(),
-            reason: "built-in test command",
-        }]);
-    }
-
-    if first == "install" && !rest.is_empty() {
-        if state.installed.contains(&rest) {
-            return Some(vec![Suggestion {
-                cmd: "echo already installed".to_string(),
-                reason: "skip reinstall",
-            }]);
-        }
-
-        // Defer to LLM unless offline; offline falls back to literal pkg name.
-        if state.config.offline {
-            let installer = installer_for(&rest, &state.config);
-            return Some(vec![install_cmd(
-                &installer,
-                &rest,
-                &state.config,
-                "install package",
-            )]);
-        }
-
-        return None;
-    }
-
-    if ["remove", "uninstall", "delete"].contains(&first) && !rest.is_empty() {
-        let installer = installer_for(&rest, &state.config);
-        let base = if installer.contains("pacman") {
-            format!("{installer} -Rsn {rest}")
+    if !on_arch_based_system() {
+        let note = if state.config.strict_distro {
+            "package operations are disabled (--strict-distro)"
         } else {
-            format!("{installer} -R {rest}")
+            "package commands may do harm here; pass --strict-distro to refuse them"
         };
-        return Some(vec![Suggestion {
-            cmd: apply_pkg_flags(base, &state.config),
-            reason: "remove package",
-        }]);
-    }
-
-    if ["open", "launch", "start"].contains(&first) && !rest.is_empty() {
-        if state.installed.contains(&rest) {
-            return Some(vec![Suggestion {
-                cmd: format!("launch {rest}"),
-                reason: "already installed",
-            }]);
-        }
-
-        if state.config.offline {
-            if let Some(install) = build_install_command(&rest, "-S --needed", &state.config) {
-                return Some(vec![
-                    Suggestion {
-                        cmd: install,
-                        reason: "ensure app is installed",
-                    },
-                    Suggestion {
-                        cmd: rest.clone(),
-                        reason: "launch app",
-                    },
-                ]);
-            }
-            // fallback to previous behavior if resolution failed
-            let installer = installer_for(&rest, &state.config);
-            return Some(vec![
-                install_cmd(&installer, &rest, &state.config, "ensure app is installed"),
-                Suggestion {
-                    cmd: format!("{rest}"),
-                    reason: "launch app",
-                },
-            ]);
-        }
-
-        // Non-offline: let LLM handle fuzzy package mapping
-        return None;
-    }
-
-    if lower.contains("fix sound") || lower.contains("fix audio") || lower.contains("sound") {
-        return Some(vec![
-            Suggestion {
-                cmd: "systemctl --user restart pipewire wireplumber".to_string(),
-                reason: "restart audio services",
-            },
-            Suggestion {
-                cmd: "pactl info".to_string(),
-                reason: "inspect pulse server state",
-            },
-        ]);
-    }
-
-    if lower.contains("fix internet") || lower.contains("fix network") || lower.contains("network") {
-        return Some(vec![
-            Suggestion {
-                cmd: "sudo systemctl restart NetworkManager".to_string(),
-                reason: "restart network manager",
-            },
-            Suggestion {
-                cmd: "nmcli networking on".to_string(),
-                reason: "enable networking",
-            },
-            Suggestion {
-                cmd: "nmcli -t -f DEVICE,STATE d".to_string(),
-                reason: "list device states",
-            },
-        ]);
-    }
-
-    if lower.contains("fix time") || lower.contains("time sync") || lower.contains("clock") {
-        return Some(vec![
-            Suggestion {
-                cmd: "sudo timedatectl set-ntp true".to_string(),
-                reason: "enable NTP sync",
-            },
-            Suggestion {
-                cmd: "timedatectl status".to_string(),
-                reason: "show time sync status",
-            },
-        ]);
-    }
-
-    if lower.contains("upgrade system") || lower.contains("update system") || first == "upgrade" {
-        let installer = installer_for("base", &state.config);
-        let base = format!("{installer} -Syu");
-        return Some(vec![Suggestion {
-            cmd: apply_pkg_flags(base, &state.config),
-            reason: "upgrade system packages",
-        }]);
-    }
-
-    if lower.contains("clean cache") || lower.contains("cleanup") || lower.contains("clear cache") {
-        let installer = installer_for("base", &state.config);
-        let base = format!("{installer} -Sc");
-        return Some(vec![Suggestion {
-            cmd: apply_pkg_flags(base, &state.config),
-            reason: "clean package cache",
-        }]);
-    }
-
-    if lower.contains("wifi status") || lower.contains("network status") {
-        return Some(vec![
-            Suggestion {
-                cmd: "nmcli general status".to_string(),
-                reason: "show network status",
-            },
-            Suggestion {
-                cmd: "nmcli -t -f DEVICE,STATE d".to_string(),
-                reason: "list device connectivity",
-            },
-        ]);
-    }
-
-    if lower.contains("fix bluetooth") || lower.contains("bluetooth") {
-        return Some(vec![
-            Suggestion {
-                cmd: "sudo systemctl restart bluetooth".to_string(),
-                reason: "restart bluetooth service",
-            },
-            Suggestion {
-                cmd: "bluetoothctl show".to_string(),
-                reason: "show bluetooth adapter state",
-            },
-        ]);
-    }
-
-    if ["logs", "journal"].contains(&first) && !rest.is_empty() {
-        return Some(vec![Suggestion {
-            cmd: format!("journalctl -u {rest} --no-pager -n 50"),
-            reason: "tail service logs",
-        }]);
-    }
-
-    None
-}
-
-fn run(cmd: &str, state: &mut AppState) -> Result<(), AssistError> {
-    println!("{cmd}");
-
-    if state.config.dry_run {
-        return Ok(());
-    }
-
-    let parts = shell_split(cmd).map_err(|e| AssistError::CommandFailed(format!("{cmd} ({e})")))?;
-    let mut iter = parts.iter();
-    let prog = iter.next().ok_or_else(|| AssistError::CommandFailed(cmd.into()))?;
-    let args: Vec<&str> = iter.map(|s| s.as_str()).collect();
-
-    let status = Command::new(prog)
-        .args(&args)
-        .stdin(Stdio::null())
-        .spawn()
-        .map_err(|e| {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                AssistError::CommandFailed(format!("{prog} not found; install or adjust PATH"))
-            } else {
-                AssistError::CommandFailed(format!("{cmd} ({e})"))
-            }
-        })?
-        .wait()
-        .map_err(|e| AssistError::CommandFailed(format!("{cmd} ({e})")))?;
-
-    if state.config.verbose {
-        eprintln!("-> {cmd} exited with {}", status);
-    }
-
-    if !status.success() {
-        return Err(AssistError::CommandFailed(format!("{cmd} exited with {status}")));
+        let line = format!("warning: this does not look like an Arch-based system; {note}");
+        eprintln!("{}", Paint::Yellow.apply(&line, color, true));
     }
 
-    update_installed_state(cmd, &status, state);
-
-    Ok(())
-}
-
-fn validate(cmd: &str) -> Result<(), AssistError> {
-    const FORBIDDEN: [&str; 12] = [
-        "|", ">", "<", "&&", "||", ";", "`", "$(", "rm -rf", "mkfs", "dd ", " :",
-    ];
-    for bad in FORBIDDEN {
-        if cmd.contains(bad) {
-            return Err(AssistError::Unsafe(cmd.into()));
-        }
-    }
-
-    // Minimal allowlist on the leading token
-    let mut parts = cmd.split_whitespace();
-    let first = parts.next().unwrap_or("");
-    let allowed = [
-        "sudo",
-        "pacman",
-        "paru",
-        "systemctl",
-        "nmcli",
-        "pactl",
-        "bluetoothctl",
-        "journalctl",
-        "timedatectl",
-        "echo",
-        "launch",
-    ];
-    let allowed_program = allowed.contains(&first);
-    if !allowed_program {
-        return Err(AssistError::Unsafe(cmd.into()));
-    }
-
-    Ok(())
-}
-
-fn apply_pkg_flags(cmd: String, config: &ExecConfig) -> String {
-    if config.yes
-        && (cmd.starts_with("sudo pacman ") || cmd.starts_with("pacman ") || cmd.starts_with("paru "))
-        && !cmd.contains("--noconfirm")
-    {
-        return format!("{cmd} --noconfirm");
-    }
-    cmd
-}
-
-fn install_cmd(installer: &str, pkg: &str, config: &ExecConfig, reason: &'static str) -> Suggestion {
-    Suggestion {
-        cmd: apply_pkg_flags(format!("{installer} -S --needed {pkg}"), config),
-        reason,
-    }
-}
-
-fn confirm(_suggestions: &[Suggestion], config: &ExecConfig) -> Result<bool, AssistError> {
-    if config.yes {
-        return Ok(true);
-    }
-    print!("Run these commands? [y/N] ");
-    io::stdout()
-        .flush()
-        .map_err(|e| AssistError::CommandFailed(format!("confirm ({e})")))?;
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .map_err(|e| AssistError::CommandFailed(format!("confirm ({e})")))?;
-    Ok(matches!(input.trim(), "y" | "Y" | "yes" | "YES"))
-}
-
-fn ensure_offline_ok(suggestion: &Suggestion, config: &ExecConfig) -> Result<(), AssistError> {
-    if !config.offline {
+    if cli.clear_installed {
+        fs::write(&cli.installed_file, "").map_err(|e| AssistError::CommandFailed(format!("clear installed ({e})")))?;
+        println!("Cleared installed list at {}", cli.installed_file.display());
         return Ok(());
     }
-    let cmd = suggestion.cmd.as_str();
-    let is_pkg_op = cmd.contains(" pacman -S")
-        || cmd.contains(" pacman -Syu")
-        || cmd.contains("paru -S")
-        || cmd.starts_with("pacman -S")
-        || cmd.starts_with("paru -S")
-        || cmd.starts_with("sudo pacman -S");
-    if is_pkg_op {
-        return Err(AssistError::Unsafe(format!(
-            "offline mode: blocked network command: {}",
-            suggestion.cmd
-        )));
-    }
-    Ok(())
-}
-
-fn load_installed(path: &Path) -> HashSet<String> {
-    if let Ok(data) = fs::read_to_string(path) {
-        return data
-            .lines()
-            .map(|l| l.trim())
-            .filter(|l| !l.is_empty())
-            .map(|l| l.to_string())
-            .collect();
-    }
-    HashSet::new()
-}
-
-fn save_installed(state: &AppState) -> Result<(), AssistError> {
-    let mut pkgs: Vec<_> = state.installed.iter().cloned().collect();
-    pkgs.sort();
-    fs::write(&state.installed_file, pkgs.join("\n"))
-        .map_err(|e| AssistError::CommandFailed(format!("save installed ({e})")))
-}
-
-fn update_installed_state(cmd: &str, status: &std::process::ExitStatus, state: &mut AppState) {
-    if !status.success() {
-        return;
-    }
-    let trimmed = cmd.trim();
-    let parts: Vec<&str> = trimmed.split_whitespace().collect();
-    if parts.is_empty() {
-        return;
-    }
-
-    let mut idx = 0;
-    let first = parts[0];
-    let mut installer = first;
-    if first == "sudo" && parts.len() > 1 {
-        installer = parts[1];
-        idx = 1;
-    }
-
-    if installer == "pacman" || installer == "paru" {
-        if parts.len() > idx + 1 {
-            let op = parts[idx + 1];
-            if op.starts_with("-S") {
-                if let Some(pkg) = parts.last() {
-                    state.installed.insert(pkg.to_string());
-                    let _ = save_installed(state);
-                }
-            }
-            if op.starts_with("-R") {
-                if let Some(pkg) = parts.last() {
-                    state.installed.remove(*pkg);
-                    let _ = save_installed(state);
-                }
-            }
-        }
-    }
-}
-
-fn llm_translate(prompt: &str, state: &AppState) -> Result<Vec<String>, AssistError> {
-    if state.config.offline {
-        return Err(AssistError::CommandFailed(
-            "offline mode: LLM suggestions disabled".into(),
-        ));
-    }
-
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .map_err(|_| AssistError::CommandFailed("OPENAI_API_KEY not set".into()))?;
-
-    let client = HttpClient::new();
-    let installed_list = if state.installed.is_empty() {
-        "none".to_string()
-    } else {
-        state
-            .installed
-            .iter()
-            .cloned()
-            .collect::<Vec<_>>()
-            .join(", ")
-    };
-
-    let system_prompt = format!(
-        "You are an Arch Linux expert. Installed packages (names only): {installed}. \
-Respond with ONLY shell commands, one per line. Use pacman for repo packages; use paru for AUR packages (e.g., *-bin). \
-Do not suggest generic shells (bash/sh) as commands. Never use dangerous operators (rm, dd, mkfs, pipes, redirects). \
-Keep responses concise and focused on the requested task.",
-        installed = installed_list
-    );
-    let model = std::env::var("OPENAI_MODEL").unwrap_or_else(|_| "gpt-4o-mini".to_string());
-
-    let req_body = ChatRequest {
-        model,
-        max_completion_tokens: Some(150),
-        temperature: Some(1.0),
-        messages: vec![
-            ChatMessage {
-                role: "system".to_string(),
-                content: vec![ChatContent {
-                    kind: "text".to_string(),
-                    text: system_prompt.to_string(),
-                }],
-            },
-            ChatMessage {
-                role: "user".to_string(),
-                content: vec![ChatContent {
-                    kind: "text".to_string(),
-                    text: prompt.to_string(),
-                }],
-            },
-        ],
-    };
-
-    let resp: ChatResponse = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {api_key}"))
-        .header("Content-Type", "application/json")
-        .json(&req_body)
-        .send()
-        .map_err(|e| AssistError::CommandFailed(format!("llm call ({e})")))?
-        .error_for_status()
-        .map_err(|e| AssistError::CommandFailed(format!("llm call ({e})")))?
-        .json()
-        .map_err(|e| AssistError::CommandFailed(format!("llm decode ({e})")))?;
-
-    if resp.choices.is_empty() {
-        return Err(AssistError::CommandFailed(
-            "LLM returned no choices".into(),
-        ));
-    }
-
-    let content_raw = resp
-        .choices
-        .first()
-        .and_then(|c| c.message.content.clone())
-        .ok_or_else(|| AssistError::CommandFailed("LLM returned no content".into()))?;
-
-    if state.config.verbose {
-        eprintln!("LLM raw content: {}", content_raw);
-    }
-
-    let content = content_raw.trim();
-    if content.is_empty() {
-        return Err(AssistError::CommandFailed(
-            "LLM returned only whitespace".into(),
-        ));
-    }
-
-    use std::collections::HashSet;
-    let mut seen = HashSet::new();
-    let mut cmds: Vec<String> = Vec::new();
-    for line in content.lines() {
-        let clean = line.trim_matches('`').trim();
-        if clean.is_empty() {
-            continue;
-        }
-        if seen.insert(clean.to_string()) {
-            cmds.push(clean.to_string());
-        }
-    }
-
-    if cmds.is_empty() {
-        return Err(AssistError::CommandFailed(
-            "LLM returned an empty command list".into(),
-        ));
-    }
-
-    let mut safe_cmds = Vec::new();
-    for cmd in cmds {
-        if validate(&cmd).is_ok() {
-            safe_cmds.push(cmd);
-        }
-    }
-
-    if safe_cmds.is_empty() {
-        return Err(AssistError::CommandFailed(
-            "LLM produced no safe commands (blocked or unsupported)".into(),
-        ));
-    }
-
-    let adjusted = adjust_commands_for_intent(safe_cmds, prompt);
-
-    let remapped: Vec<String> = adjusted
-        .into_iter()
-        .map(|cmd| rewrite_install_with_resolution(cmd, &state.config))
-        .collect();
-
-    // If this was a launch intent and we only have installs, add a launch step
-    if is_launch_intent(prompt) && !remapped.iter().any(|c| c.starts_with("launch ")) {
-        if let Some(app) = extract_app_name_from_install(&remapped) {
-            let mut with_launch = remapped.clone();
-            with_launch.push(format!("launch {}", app));
-            return Ok(with_launch);
-        }
-    }
-
-    Ok(remapped)
-}
 
-fn adjust_commands_for_intent(cmds: Vec<String>, prompt: &str) -> Vec<String> {
-    let prompt_lower = prompt.to_lowercase();
-    let desired_pkg = if prompt_lower.contains("word") || prompt_lower.contains("office") {
-        Some("libreoffice-fresh")
-    } else {
-        None
+    let runner = ProcessRunner {
+        capture: state.config.capture,
+        json: state.config.json,
+        pager: state.config.pager,
+        sudo_askpass: state.config.sudo_askpass.clone(),
     };
-    let is_launch_intent = is_launch_intent(&prompt_lower);
-
-    let mut out = Vec::new();
-    for cmd in &cmds {
-        // Drop suggestions that install helper tools we don't want
-        if cmd.contains(" yay") || cmd.starts_with("yay ") || cmd == "yay" {
-            continue;
-        }
-
-        if let Some(pkg) = desired_pkg {
-            if let Some(rewritten) = rewrite_install_pkg(cmd, pkg) {
-                out.push(rewritten);
-                continue;
-            }
-        }
-
-        if is_launch_intent && needs_launch_wrapper(cmd) {
-            out.push(format!("launch {}", cmd));
-            continue;
-        }
-
-        out.push(cmd.clone());
-    }
-
-    if out.is_empty() {
-        return cmds;
-    }
-
-    out
-}
-
-fn rewrite_install_pkg(cmd: &str, new_pkg: &str) -> Option<String> {
-    let parts: Vec<&str> = cmd.split_whitespace().collect();
-    if parts.len() < 2 {
-        return None;
-    }
-
-    let (tool, rest) = (parts[0], &parts[1..]);
-    if tool != "pacman" && tool != "paru" && !(tool == "sudo" && rest.first() == Some(&"pacman")) {
-        return None;
-    }
-
-    let mut installer = tool;
-    let mut args = rest;
-    if tool == "sudo" && rest.first() == Some(&"pacman") {
-        installer = "sudo pacman";
-        args = &rest[1..];
-    }
-
-    if args.is_empty() || !args[0].starts_with("-S") {
-        return None;
-    }
-
-    let mut new_args = args.to_vec();
-    if let Some(last) = new_args.last_mut() {
-        *last = new_pkg;
-    }
-    let rebuilt = format!("{} {}", installer, new_args.join(" "));
-    Some(rebuilt)
-}
-
-fn needs_launch_wrapper(cmd: &str) -> bool {
-    let allowed = [
-        "sudo", "pacman", "paru", "systemctl", "nmcli", "pactl", "bluetoothctl", "journalctl",
-        "timedatectl", "echo", "launch",
-    ];
-    let mut parts = cmd.split_whitespace();
-    let first = parts.next().unwrap_or("");
-    if allowed.contains(&first) {
-        return false;
-    }
-    // If it's a single token (likely app name), wrap it
-    !cmd.contains(' ')
-}
-
-fn extract_app_name_from_install(cmds: &[String]) -> Option<String> {
-    for cmd in cmds {
-        let parts: Vec<&str> = cmd.split_whitespace().collect();
-        if parts.len() >= 2 && parts[0] == "launch" {
-            return Some(parts[1].to_string());
-        }
-        if parts.len() >= 3 && parts[0] == "sudo" && parts[1] == "pacman" && parts[2].starts_with("-S") {
-            if let Some(pkg) = parts.last() {
-                return Some((*pkg).to_string());
-            }
-        }
-        if parts.len() >= 2 && parts[0] == "pacman" && parts[1].starts_with("-S") {
-            if let Some(pkg) = parts.last() {
-                return Some((*pkg).to_string());
-            }
-        }
-        if parts.len() >= 2 && parts[0] == "paru" && parts[1].starts_with("-S") {
-            if let Some(pkg) = parts.last() {
-                return Some((*pkg).to_string());
-            }
-        }
-    }
-    None
-}
-
-fn is_launch_intent(prompt: &str) -> bool {
-    let prompt_lower = prompt.to_lowercase();
-    ["open", "launch", "start"]
-        .iter()
-        .any(|k| prompt_lower.starts_with(k))
-}
-
-fn rewrite_install_with_resolution(cmd: String, config: &ExecConfig) -> String {
-    let trimmed = cmd.trim();
-    let parts: Vec<&str> = trimmed.split_whitespace().collect();
-    if parts.len() >= 3 && parts[0] == "sudo" && parts[1] == "pacman" && parts[2].starts_with("-S") {
-        if let Some(pkg) = parts.last() {
-            if let Some(cmd) = resolve_installer(parts[2..].to_vec(), pkg, config) {
-                return cmd;
-            }
-        }
-    }
-    if parts.len() >= 2 && parts[0] == "pacman" && parts[1].starts_with("-S") {
-        if let Some(pkg) = parts.last() {
-            if let Some(cmd) = resolve_installer(parts[1..].to_vec(), pkg, config) {
-                return cmd;
-            }
-        }
-    }
-    cmd
-}
-
-fn resolve_installer(flags_and_pkg: Vec<&str>, pkg: &str, config: &ExecConfig) -> Option<String> {
-    let mut rest = flags_and_pkg;
-    rest.pop(); // drop pkg
-    let flags = rest.join(" ");
-
-    let resolution = resolve_package(pkg, config);
-    match resolution {
-        PackageOrigin::Repo => {
-            let installer = if config.no_sudo { "pacman" } else { "sudo pacman" };
-            Some(format!("{installer} {} {}", flags, pkg))
-        }
-        PackageOrigin::Aur => Some(format!("paru {} {}", flags, pkg)),
-        PackageOrigin::Unknown => {
-            if is_probably_aur(pkg) {
-                Some(format!("paru {} {}", flags, pkg))
-            } else {
-                Some(format!(
-                    "{} {} {}",
-                    if config.no_sudo { "pacman" } else { "sudo pacman" },
-                    flags,
-                    pkg
-                ))
-            }
-        }
-        PackageOrigin::Offline => None,
-    }
-}
-
-fn build_install_command(pkg: &str, flags: &str, config: &ExecConfig) -> Option<String> {
-    let resolution = resolve_package(pkg, config);
-    match resolution {
-        PackageOrigin::Repo => {
-            let installer = if config.no_sudo { "pacman" } else { "sudo pacman" };
-            Some(format!("{installer} {flags} {pkg}"))
-        }
-        PackageOrigin::Aur => Some(format!("paru {flags} {pkg}")),
-        PackageOrigin::Unknown => {
-            if is_probably_aur(pkg) {
-                Some(format!("paru {flags} {pkg}"))
-            } else {
-                Some(format!(
-                    "{} {flags} {}",
-                    if config.no_sudo { "pacman" } else { "sudo pacman" },
-                    pkg
-                ))
-            }
+    match cli.command {
+        Commands::Ai { prompt } if prompt == "-" => {
+            let script =
+                io::read_to_string(io::stdin()).map_err(|e| AssistError::CommandFailed(format!("read stdin ({e})")))?;
+            handle_prompt_script(&script, &mut state, &runner)?;
         }
-        PackageOrigin::Offline => None,
-    }
-}
-
-fn is_probably_aur(pkg: &str) -> bool {
-    let aur_suffixes = ["-bin", "-git", "-svn", "-hg"];
-    if aur_suffixes.iter().any(|s| pkg.ends_with(s)) {
-        return true;
-    }
-
-    let common_aur = [
-        "google-chrome",
-        "brave-bin",
-        "microsoft-edge-stable-bin",
-        "visual-studio-code-bin",
-        "wps-office",
-        "slack-desktop",
-        "zoom",
-        "spotify",
-    ];
-
-    common_aur.contains(&pkg)
-}
-
-enum PackageOrigin {
-    Repo,
-    Aur,
-    Unknown,
-    Offline,
-}
-
-fn resolve_package(pkg: &str, config: &ExecConfig) -> PackageOrigin {
-    if config.offline {
-        return PackageOrigin::Offline;
-    }
-
-    if check_arch_repo(pkg) {
-        return PackageOrigin::Repo;
-    }
-
-    if check_aur(pkg) {
-        return PackageOrigin::Aur;
-    }
-
-    PackageOrigin::Unknown
-}
-
-fn check_arch_repo(pkg: &str) -> bool {
-    let client = HttpClient::new();
-    let url = format!(
-        "https://archlinux.org/packages/search/json/?q={}",
-        urlencoding::encode(pkg)
-    );
-    if let Ok(resp) = client.get(url).send() {
-        if let Ok(json) = resp.json::<ArchSearch>() {
-            return !json.results.is_empty();
+        Commands::Ai { prompt } => handle_prompt(&prompt, &mut state, &runner)?,
+        Commands::Run { command } => {
+            validate(&command, &state.config)?;
+            run(&command, &mut state, &runner)?;
         }
-    }
-    false
-}
-
-fn check_aur(pkg: &str) -> bool {
-    let client = HttpClient::new();
-    let url = format!(
-        "https://aur.archlinux.org/rpc/?v=5&type=info&arg={}",
-        urlencoding::encode(pkg)
-    );
-    if let Ok(resp) = client.get(url).send() {
-        if let Ok(json) = resp.json::<AurInfo>() {
-            return json.resultcount.unwrap_or(0) > 0;
+        Commands::History { replay, last } => handle_history(replay, last, &mut state, &runner)?,
+        Commands::Search {
+            query,
+            aur_only,
+            repo_only,
+        } => handle_search(&query, repo_only, aur_only, &state.config)?,
+        Commands::Intents => handle_intents(&state)?,
+        Commands::Translate { prompt } => {
+            handle_translate(&prompt, &state)?;
         }
+        Commands::Explain { command } => handle_explain(&command, &state.config)?,
+        Commands::Info { pkg } => handle_info(&pkg, &state.config)?,
+        Commands::Doctor => handle_doctor(&state.config)?,
+        Commands::Completions { .. } => unreachable!("handled before config is loaded"),
     }
-    false
-}
-
-#[derive(Serialize)]
-struct ChatMessage {
-    role: String,
-    content: Vec<ChatContent>,
-}
-
-#[derive(Serialize)]
-struct ChatContent {
-    #[serde(rename = "type")]
-    kind: String,
-    text: String,
-}
-
-#[derive(Serialize)]
-struct ChatRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    max_completion_tokens: Option<u32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    temperature: Option<f32>,
-}
 
-#[derive(Deserialize)]
-struct ChatResponse {
-    choices: Vec<Choice>,
-}
-
-#[derive(Deserialize)]
-struct Choice {
-    message: LlmMessage,
-}
-
-#[derive(Deserialize)]
-struct LlmMessage {
-    content: Option<String>,
-}
-
-#[derive(Deserialize)]
-struct ArchSearch {
-    results: Vec<ArchResult>,
-}
-
-#[derive(Deserialize)]
-struct ArchResult {
-    #[allow(dead_code)]
-    pkgname: String,
+    Ok(())
 }
 
-#[derive(Deserialize)]
-struct AurInfo {
-    resultcount: Option<u32>,
+/// An explicit `--x` or `--no-x` beats config.toml; with neither, the file value stands.
+fn flag(on: bool, off: bool, file: bool) -> bool {
+    (on || file) && !off
 }